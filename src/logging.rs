@@ -0,0 +1,64 @@
+use crate::configuration::{ConfigLoader, CONF_LOG_LEVEL};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Clone, Copy)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+static LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+
+impl Level {
+    pub fn parse(name: &str) -> Option<Level> {
+        match name.to_lowercase().as_str() {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+}
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+/// Applies the configured `log_level`. The value is validated when the configuration is loaded.
+pub fn apply_config(config_loader: &mut ConfigLoader) {
+    if let Some(level) = Level::parse(&CONF_LOG_LEVEL.get_value(config_loader)) {
+        set_level(level);
+    }
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as usize <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Prints the message when the configured `log_level` includes `$level`.
+macro_rules! log {
+    ($level:ident, $($arg:tt)*) => {
+        if crate::logging::enabled(crate::logging::Level::$level) {
+            println!($($arg)*);
+        }
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => { log!(Error, $($arg)*) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => { log!(Warn, $($arg)*) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { log!(Info, $($arg)*) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { log!(Debug, $($arg)*) };
+}
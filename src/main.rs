@@ -1,61 +1,265 @@
 extern crate ctrlc;
 use crate::configuration::ConfigLoader;
-use crate::configuration::{CONF_PREFIX, CONF_TOKEN};
+use crate::configuration::{CONF_PREFIX, CONF_TOKEN, OPTIONS};
+use serenity::client::Context;
+use serenity::framework::standard::{DispatchError, Reason};
 use serenity::framework::StandardFramework;
+use serenity::model::gateway::Ready;
+use serenity::model::id::GuildId;
+use serenity::model::voice::VoiceState;
 use serenity::prelude::EventHandler;
 use serenity::prelude::Mutex;
 use serenity::Client;
+use std::collections::HashSet;
 use std::env;
 use std::process::exit;
 use std::sync::Arc;
 
+#[macro_use]
+mod logging;
 mod configuration;
 mod music;
+mod permissions;
+mod settings;
 
 use music::voice_client;
 
 struct Handler;
 
-impl EventHandler for Handler {}
+impl EventHandler for Handler {
+    fn ready(&self, ctx: Context, _: Ready) {
+        voice_client::rejoin_voice_channels(&ctx);
+    }
+
+    fn voice_state_update(
+        &self,
+        ctx: Context,
+        guild_id: Option<GuildId>,
+        _: Option<VoiceState>,
+        _: VoiceState,
+    ) {
+        if let Some(guild_id) = guild_id {
+            voice_client::voice_state_update(&ctx, guild_id);
+        }
+    }
+}
+
+fn usage(program: &str) -> String {
+    format!(
+        "Usage: {} [options] [config]
+
+Options:
+    --config <path>         Configuration file, config.json by default
+    --token-file <path>     Read the bot token from a file
+    --prefix <prefix>       Command prefix
+    --autoplaylist <path>   Autoplaylist file
+    --log-level <level>     One of error, warn, info or debug
+    --print-config          Print the effective configuration and exit
+    -h, --help              Print this help
+
+Every option of the configuration file can also be set with a DMB_<OPTION> environment
+variable, e.g. DMB_PREFIX or DMB_TOKEN. DMB_TOKEN_FILE works like --token-file. Command line
+flags take precedence over the environment, which takes precedence over the file.",
+        program
+    )
+}
+
+/// Command line flags that override configuration options, by option key.
+const FLAGS: [(&str, &str); 3] = [
+    ("--prefix", "prefix"),
+    ("--autoplaylist", "autoplaylist_path"),
+    ("--log-level", "log_level"),
+];
+
+struct Arguments {
+    config_path: Option<String>,
+    token_file: Option<String>,
+    overrides: Vec<(&'static str, String)>,
+    print_config: bool,
+}
+
+fn parse_arguments(args: &[String]) -> Result<Arguments, String> {
+    let mut arguments = Arguments {
+        config_path: None,
+        token_file: None,
+        overrides: Vec::new(),
+        print_config: false,
+    };
+
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        let mut value = || {
+            iter.next()
+                .cloned()
+                .ok_or_else(|| format!("{} needs a value", arg))
+        };
+        match arg.as_str() {
+            "--config" => arguments.config_path = Some(value()?),
+            "--token-file" => arguments.token_file = Some(value()?),
+            "--print-config" => arguments.print_config = true,
+            flag if flag.starts_with("--") => match FLAGS.iter().find(|(name, _)| *name == flag) {
+                Some((_, key)) => arguments.overrides.push((key, value()?)),
+                None => return Err(format!("Unknown flag {}", flag)),
+            },
+            path if arguments.config_path.is_none() => {
+                arguments.config_path = Some(path.to_string())
+            }
+            _ => return Err(format!("Unexpected argument {}", arg)),
+        }
+    }
+    Ok(arguments)
+}
+
+/// Layers the configuration: defaults, the file, the environment and the command line.
+fn load_configuration(arguments: &Arguments) -> Result<ConfigLoader, Vec<String>> {
+    let mut config_loader = match &arguments.config_path {
+        Some(path) => ConfigLoader::new(path),
+        None => ConfigLoader::default(),
+    };
+    config_loader.load()?;
+    config_loader.apply_environment()?;
+
+    let mut errors = Vec::new();
+    if let Some(path) = &arguments.token_file {
+        if let Err(err) = config_loader.set_token_file(path, "command line") {
+            errors.push(format!("--token-file: {}", err));
+        }
+    }
+    for (key, input) in &arguments.overrides {
+        let option = OPTIONS.iter().find(|option| option.key() == *key).unwrap();
+        if let Err(err) = option
+            .parse(input)
+            .and_then(|value| config_loader.set_override(key, value, "command line"))
+        {
+            errors.push(err);
+        }
+    }
+    // The overrides may have moved the guild configuration file.
+    if let Err(guild_errors) = config_loader.load_guild_values() {
+        errors.extend(guild_errors);
+    }
+
+    if errors.is_empty() {
+        Ok(config_loader)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Every option with its effective value and where it comes from, as `--print-config` shows
+/// them. The token is masked.
+fn describe_configuration(config_loader: &mut ConfigLoader) -> Vec<String> {
+    OPTIONS
+        .iter()
+        .map(|option| {
+            let source = config_loader.source(option.key());
+            format!(
+                "{} = {} ({})",
+                option.key(),
+                option.display(config_loader),
+                source
+            )
+        })
+        .collect()
+}
 
 fn main() {
-    let mut config_loader = {
-        let args: Vec<String> = env::args().collect();
-        if args.len() > 2 {
-            println!("Usage: ./{} [config]", args[0]);
+    let args: Vec<String> = env::args().collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", usage(&args[0]));
+        return;
+    }
+
+    let arguments = match parse_arguments(&args) {
+        Ok(arguments) => arguments,
+        Err(err) => {
+            println!("{}\n\n{}", err, usage(&args[0]));
             exit(1);
         }
+    };
 
-        match args.len() {
-            2 => configuration::ConfigLoader::new(&args[1]),
-            _ => configuration::ConfigLoader::default(),
+    let mut config_loader = match load_configuration(&arguments) {
+        Ok(config_loader) => config_loader,
+        Err(errors) => {
+            println!("Invalid configuration:");
+            for error in errors {
+                println!("  {}", error);
+            }
+            exit(1);
         }
     };
 
+    if arguments.print_config {
+        for line in describe_configuration(&mut config_loader) {
+            println!("{}", line);
+        }
+        return;
+    }
+
+    logging::apply_config(&mut config_loader);
+
     let token = CONF_TOKEN.get_value(&mut config_loader);
     if token.is_empty() {
-        println!("Token is empty!");
+        error!("Token is empty!");
         exit(1);
     }
 
     let mut client = Client::new(token, Handler).expect("Couldn't create client");
     let shard_manager = client.shard_manager.clone();
     let data = client.data.clone();
+    let owners = match client.cache_and_http.http.get_current_application_info() {
+        Ok(info) => vec![info.owner.id].into_iter().collect(),
+        Err(why) => {
+            warn!("Couldn't get application info: {:?}", why);
+            HashSet::new()
+        }
+    };
+
+    // Guilds may override the prefix, so it's looked up for every message instead of being fixed
+    // when the framework is built.
     let mut standard_framework = StandardFramework::new()
-        .configure(|c| c.prefix(&CONF_PREFIX.get_value(&mut config_loader)));
+        .configure(|c| {
+            c.owners(owners).dynamic_prefix(|ctx, msg| {
+                let config_loader = ctx
+                    .data
+                    .read()
+                    .get::<ConfigLoader>()
+                    .expect("Expected ConfigLoader in ShareMap.")
+                    .clone();
+                let mut config_loader = config_loader.lock();
+                Some(match msg.guild_id {
+                    Some(guild_id) => CONF_PREFIX.get_guild_value(&mut config_loader, guild_id),
+                    None => CONF_PREFIX.get_value(&mut config_loader),
+                })
+            })
+        })
+        .on_dispatch_error(|ctx, msg, error| {
+            let reply = match error {
+                DispatchError::CheckFailed(_, Reason::User(reason)) => reason,
+                DispatchError::OnlyForGuilds => "This command only works in a guild".to_string(),
+                error => {
+                    debug!("Not running {:?}: {:?}", msg.content, error);
+                    return;
+                }
+            };
+            let _ = msg.channel_id.say(&ctx.http, format!("```{}```", reply));
+        });
 
     data.write()
         .insert::<ConfigLoader>(Arc::new(Mutex::new(config_loader)));
 
-    println!("Initializing...");
-    println!("Voice module...");
+    info!("Initializing...");
+    info!("Voice module...");
     voice_client::register_module(&mut client, &mut standard_framework);
-    println!("Voice module done");
+    info!("Voice module done");
+    info!("Settings module...");
+    settings::register_module(&mut client, &mut standard_framework);
+    info!("Settings module done");
 
     client.with_framework(standard_framework);
 
     ctrlc::set_handler(move || {
-        println!("\nShutting down...");
+        info!("\nShutting down...");
         voice_client::unregister_module(data.clone());
         shard_manager.lock().shutdown_all();
     })
@@ -63,5 +267,142 @@ fn main() {
 
     let _ = client
         .start()
-        .map_err(|why| println!("Client ended: {:?}", why));
+        .map_err(|why| error!("Client ended: {:?}", why));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::{CONF_AUTOPLAYLIST_PATH, CONF_LOG_LEVEL};
+    use std::fs;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        std::iter::once("discord_music_bot")
+            .chain(args.iter().copied())
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn temporary_path(name: &str) -> String {
+        env::temp_dir()
+            .join(format!("{}-{}", name, std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn parse_arguments_reads_flags_and_the_config_path() {
+        let arguments = parse_arguments(&args(&[
+            "--prefix",
+            "!",
+            "--print-config",
+            "bot.json",
+            "--log-level",
+            "debug",
+        ]))
+        .unwrap();
+        assert_eq!(arguments.config_path.as_deref(), Some("bot.json"));
+        assert!(arguments.token_file.is_none());
+        assert!(arguments.print_config);
+        assert_eq!(
+            arguments.overrides,
+            vec![
+                ("prefix", "!".to_string()),
+                ("log_level", "debug".to_string())
+            ]
+        );
+
+        let arguments = parse_arguments(&args(&["--config", "bot.json"])).unwrap();
+        assert_eq!(arguments.config_path.as_deref(), Some("bot.json"));
+        assert!(!arguments.print_config);
+    }
+
+    #[test]
+    fn parse_arguments_rejects_bad_input() {
+        assert_eq!(
+            parse_arguments(&args(&["--volume", "50"])).err(),
+            Some("Unknown flag --volume".to_string())
+        );
+        assert_eq!(
+            parse_arguments(&args(&["--prefix"])).err(),
+            Some("--prefix needs a value".to_string())
+        );
+        assert_eq!(
+            parse_arguments(&args(&["--token-file"])).err(),
+            Some("--token-file needs a value".to_string())
+        );
+        assert_eq!(
+            parse_arguments(&args(&["a.json", "b.json"])).err(),
+            Some("Unexpected argument b.json".to_string())
+        );
+    }
+
+    /// The only test touching `DMB_` variables, since the environment is shared by all tests.
+    #[test]
+    fn flags_override_the_environment_which_overrides_the_file() {
+        let config_path = temporary_path("layering-config.json");
+        let token_path = temporary_path("layering-token");
+        fs::write(
+            &config_path,
+            r#"{"prefix": "file", "autoplaylist_path": "file.txt", "log_level": "warn", "token": "file-token"}"#,
+        )
+        .unwrap();
+        fs::write(&token_path, "secret-token\n").unwrap();
+        env::set_var("DMB_PREFIX", "env");
+        env::set_var("DMB_AUTOPLAYLIST_PATH", "env.txt");
+
+        let arguments = parse_arguments(&args(&[
+            &config_path,
+            "--autoplaylist",
+            "flag.txt",
+            "--token-file",
+            &token_path,
+            "--print-config",
+        ]))
+        .unwrap();
+        let loaded = load_configuration(&arguments);
+        env::remove_var("DMB_PREFIX");
+        env::remove_var("DMB_AUTOPLAYLIST_PATH");
+        fs::remove_file(&config_path).unwrap();
+        fs::remove_file(&token_path).unwrap();
+        let mut config_loader = loaded.unwrap();
+
+        assert_eq!(CONF_PREFIX.get_value(&mut config_loader), "env");
+        assert_eq!(config_loader.source("prefix"), "environment");
+        assert_eq!(
+            CONF_AUTOPLAYLIST_PATH.get_value(&mut config_loader),
+            "flag.txt"
+        );
+        assert_eq!(config_loader.source("autoplaylist_path"), "command line");
+        assert_eq!(CONF_LOG_LEVEL.get_value(&mut config_loader), "warn");
+        assert_eq!(config_loader.source("log_level"), "file");
+        assert_eq!(config_loader.source("idle_timeout"), "default");
+        assert_eq!(CONF_TOKEN.get_value(&mut config_loader), "secret-token");
+
+        let lines = describe_configuration(&mut config_loader);
+        assert!(lines.contains(&"token = ******** (command line)".to_string()));
+        assert!(lines.contains(&"prefix = \"env\" (environment)".to_string()));
+        assert!(lines.iter().all(|line| !line.contains("secret-token")));
+    }
+
+    #[test]
+    fn invalid_values_are_reported_for_every_layer() {
+        let config_path = temporary_path("invalid-config.json");
+        fs::write(&config_path, r#"{"default_volume": "loud"}"#).unwrap();
+        let arguments = parse_arguments(&args(&[&config_path])).unwrap();
+        let errors = load_configuration(&arguments).err().unwrap();
+        fs::remove_file(&config_path).unwrap();
+        assert_eq!(errors.len(), 1);
+
+        let arguments = parse_arguments(&args(&[
+            "--config",
+            &temporary_path("missing-config.json"),
+            "--log-level",
+            "verbose",
+        ]))
+        .unwrap();
+        let errors = load_configuration(&arguments).err().unwrap();
+        assert_eq!(errors.len(), 1);
+    }
 }
@@ -1,3 +1,7 @@
+mod player;
 mod playlist;
+mod resolver;
+mod state;
+mod track;
 pub mod voice_client;
 mod ytdl;
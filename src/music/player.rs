@@ -0,0 +1,286 @@
+use crate::music::playlist::Request;
+use crate::music::track::TrackInfo;
+use crate::music::voice_client::VoiceManagerProperties;
+use serenity::model::id::ChannelId;
+use serenity::prelude::Mutex;
+use serenity::voice::{AudioSource, AudioType};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// What a guild's player thread is asked to do. The thread owns the guild's playback, everything
+/// else only talks to it through these.
+pub(crate) enum PlayerMessage {
+    /// The playing track ran out or was stopped.
+    TrackEnd,
+    /// Something the player waits for may have changed, e.g. a track was resolved or someone
+    /// joined the voice channel.
+    Update,
+    Enqueue(Enqueue),
+    /// Ends the current track, replying with it.
+    Skip(Sender<Option<TrackInfo>>),
+    /// Pauses or resumes the current track, replying with it and whether that changed anything.
+    Pause(bool, Sender<Option<(TrackInfo, bool)>>),
+    /// Leaves the voice channel, replying with the channel that was left.
+    Stop(Sender<Option<Option<ChannelId>>>),
+    /// Runs on the player thread with the guild's playback.
+    Query(Box<dyn FnOnce(&mut VoiceManagerProperties) + Send>),
+    /// Ends the player thread.
+    Shutdown,
+}
+
+/// Decides whether a resolved track may be queued, see `Playlist::push`.
+pub(crate) type Accept = Box<dyn FnOnce(&TrackInfo) -> Result<(), String> + Send>;
+/// Told whether the track was queued, see `Playlist::push`.
+pub(crate) type OnResolved = Box<dyn FnOnce(Result<TrackInfo, String>) + Send>;
+
+pub(crate) enum Enqueue {
+    /// Queued right away and resolved in the background, see `Playlist::push`.
+    Url {
+        url: String,
+        label: String,
+        request: Request,
+        accept: Accept,
+        on_resolved: OnResolved,
+    },
+    /// Tracks that are already resolved, e.g. the entries of a playlist.
+    Tracks(Vec<TrackInfo>, Request),
+}
+
+/// Handle to a guild's player thread. The replies are `None` once the thread ended.
+#[derive(Clone)]
+pub(crate) struct Player {
+    sender: Sender<PlayerMessage>,
+}
+
+impl Player {
+    pub(crate) fn new(sender: Sender<PlayerMessage>) -> Self {
+        Player { sender }
+    }
+
+    /// Sender for the sources and playlists that wake the player. Sending never blocks.
+    pub(crate) fn sender(&self) -> Sender<PlayerMessage> {
+        self.sender.clone()
+    }
+
+    pub(crate) fn notify(&self) {
+        let _ = self.sender.send(PlayerMessage::Update);
+    }
+
+    pub(crate) fn enqueue(&self, enqueue: Enqueue) {
+        let _ = self.sender.send(PlayerMessage::Enqueue(enqueue));
+    }
+
+    pub(crate) fn skip(&self) -> Option<TrackInfo> {
+        self.request(PlayerMessage::Skip).flatten()
+    }
+
+    pub(crate) fn pause(&self, paused: bool) -> Option<(TrackInfo, bool)> {
+        self.request(|reply| PlayerMessage::Pause(paused, reply))
+            .flatten()
+    }
+
+    /// `None` if the bot wasn't in a voice channel.
+    pub(crate) fn stop(&self) -> Option<Option<ChannelId>> {
+        self.request(PlayerMessage::Stop).flatten()
+    }
+
+    /// Runs `query` on the player thread and returns its result. Must not be called while
+    /// holding a lock the player takes, e.g. the client's voice manager.
+    pub(crate) fn query<R, Q>(&self, query: Q) -> Option<R>
+    where
+        R: Send + 'static,
+        Q: FnOnce(&mut VoiceManagerProperties) -> R + Send + 'static,
+    {
+        self.request(|reply| {
+            PlayerMessage::Query(Box::new(move |property| {
+                let _ = reply.send(query(property));
+            }))
+        })
+    }
+
+    pub(crate) fn shutdown(&self) {
+        let _ = self.sender.send(PlayerMessage::Shutdown);
+    }
+
+    fn request<R, M>(&self, message: M) -> Option<R>
+    where
+        M: FnOnce(Sender<R>) -> PlayerMessage,
+    {
+        let (reply, receiver) = mpsc::channel();
+        self.sender.send(message(reply)).ok()?;
+        receiver.recv().ok()
+    }
+}
+
+/// The voice connection asks for a frame every 20ms.
+const FRAME_DURATION: Duration = Duration::from_millis(20);
+
+/// Volume ramp over a number of frames.
+struct Fade {
+    /// Ramps down and ends the source instead of ramping up.
+    out: bool,
+    frame: u32,
+    frames: u32,
+}
+
+impl Fade {
+    fn new(out: bool, duration: Duration) -> Self {
+        Fade {
+            out,
+            frame: 0,
+            frames: (duration.as_millis() / FRAME_DURATION.as_millis()) as u32,
+        }
+    }
+
+    /// Volume factor for the next frame.
+    fn next(&mut self) -> f32 {
+        let progress = self.frame as f32 / self.frames as f32;
+        self.frame += 1;
+        if self.out {
+            1.0 - progress
+        } else {
+            progress
+        }
+    }
+
+    fn done(&self) -> bool {
+        self.frame >= self.frames
+    }
+}
+
+/// Follows a stream wrapped by `NotifyingSource` after it was handed to the voice connection.
+#[derive(Clone)]
+pub(crate) struct StreamHandle {
+    ended: Arc<AtomicBool>,
+    fade: Arc<Mutex<Option<Fade>>>,
+}
+
+impl StreamHandle {
+    /// Whether the stream ran out or was dropped.
+    pub(crate) fn ended(&self) -> bool {
+        self.ended.load(Ordering::SeqCst)
+    }
+
+    /// Ramps the stream down over `duration` and ends it.
+    pub(crate) fn fade_out(&self, duration: Duration) {
+        *self.fade.lock() = Some(Fade::new(true, duration));
+    }
+}
+
+/// Audio source that reports to the guild's player once the wrapped source runs out or is
+/// dropped by the voice connection, so the next track starts right away. It also applies fades.
+pub(crate) struct NotifyingSource {
+    source: Box<dyn AudioSource>,
+    ended: Arc<AtomicBool>,
+    fade: Arc<Mutex<Option<Fade>>>,
+    events: Sender<PlayerMessage>,
+}
+
+impl NotifyingSource {
+    /// Wraps `source`, ramping it up over `fade_in` if that isn't zero.
+    pub(crate) fn wrap(
+        source: Box<dyn AudioSource>,
+        events: Sender<PlayerMessage>,
+        fade_in: Duration,
+    ) -> (Box<dyn AudioSource>, StreamHandle) {
+        let handle = StreamHandle {
+            ended: Arc::new(AtomicBool::new(false)),
+            fade: Arc::new(Mutex::new(if fade_in >= FRAME_DURATION {
+                Some(Fade::new(false, fade_in))
+            } else {
+                None
+            })),
+        };
+        let source = NotifyingSource {
+            source,
+            ended: handle.ended.clone(),
+            fade: handle.fade.clone(),
+            events,
+        };
+        (Box::new(source), handle)
+    }
+
+    fn end(&self) {
+        if !self.ended.swap(true, Ordering::SeqCst) {
+            let _ = self.events.send(PlayerMessage::TrackEnd);
+        }
+    }
+
+    /// Passes `read` through, ending the source once it stops returning audio.
+    fn check(&self, read: Option<usize>) -> Option<usize> {
+        if read.unwrap_or(0) == 0 {
+            self.end();
+        }
+        read
+    }
+
+    /// Volume factor for the next frame, `None` once a fade-out finished.
+    fn fade_volume(&self) -> Option<f32> {
+        let mut fade = self.fade.lock();
+        let volume = match fade.as_mut() {
+            Some(fade) if fade.out && fade.done() => return None,
+            Some(fade) => fade.next(),
+            None => return Some(1.0),
+        };
+        if fade.as_ref().is_some_and(|fade| !fade.out && fade.done()) {
+            *fade = None;
+        }
+        Some(volume)
+    }
+}
+
+impl AudioSource for NotifyingSource {
+    fn is_stereo(&mut self) -> bool {
+        self.source.is_stereo()
+    }
+
+    fn get_type(&self) -> AudioType {
+        self.source.get_type()
+    }
+
+    fn read_pcm_frame(&mut self, buffer: &mut [i16]) -> Option<usize> {
+        let volume = match self.fade_volume() {
+            Some(volume) => volume,
+            None => return self.check(None),
+        };
+        let read = self.source.read_pcm_frame(buffer);
+        if volume < 1.0 {
+            for sample in &mut buffer[..read.unwrap_or(0)] {
+                *sample = (*sample as f32 * volume) as i16;
+            }
+        }
+        self.check(read)
+    }
+
+    fn read_opus_frame(&mut self) -> Option<Vec<u8>> {
+        let frame = self.source.read_opus_frame();
+        if frame.is_none() {
+            self.end();
+        }
+        frame
+    }
+
+    fn decode_and_add_opus_frame(
+        &mut self,
+        float_buffer: &mut [f32; 1920],
+        volume: f32,
+    ) -> Option<usize> {
+        let fade = match self.fade_volume() {
+            Some(fade) => fade,
+            None => return self.check(None),
+        };
+        let read = self
+            .source
+            .decode_and_add_opus_frame(float_buffer, volume * fade);
+        self.check(read)
+    }
+}
+
+impl Drop for NotifyingSource {
+    fn drop(&mut self) {
+        self.end();
+    }
+}
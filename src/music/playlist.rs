@@ -1,87 +1,218 @@
 extern crate rand;
-use crate::music::ytdl;
+use crate::music::player::PlayerMessage;
+use crate::music::resolver::TrackResolver;
+use crate::music::state::PlaylistState;
+use crate::music::track::TrackInfo;
+use crate::music::ytdl::YtdlError;
+use rand::seq::SliceRandom;
 use rand::Rng;
-use serenity::model::id::ChannelId;
+use serenity::model::id::{ChannelId, MessageId, UserId};
 use serenity::prelude::RwLock;
 use std::cmp;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::prelude::BufRead;
 use std::io::BufReader;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
 use std::sync::Arc;
 use std::thread;
-use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
 
-type Title = (String, String, Option<ChannelId>);
+/// An entry of the regular queue. Tracks are queued before their metadata is known and are
+/// identified by a per-playlist id until then.
+#[derive(Clone)]
+pub(crate) enum QueuedTrack {
+    Resolving(usize, String),
+    Resolved(TrackInfo),
+}
+
+/// Who asked for a queue entry, when and where replies about it go. Autoplaylist entries have
+/// none.
+#[derive(Clone, Copy)]
+pub(crate) struct Request {
+    pub(crate) user_id: UserId,
+    pub(crate) channel_id: ChannelId,
+    pub(crate) requested_at: SystemTime,
+    /// The command message that queued the entry, unknown for entries restored from a state file
+    /// that didn't record it.
+    pub(crate) message_id: Option<MessageId>,
+}
+
+impl QueuedTrack {
+    pub(crate) fn describe(&self) -> String {
+        match self {
+            QueuedTrack::Resolved(track) => track.describe(),
+            QueuedTrack::Resolving(_, label) => format!("{} [resolving]", label),
+        }
+    }
+}
+
+/// First wait before resolving autoplaylist entries again after the extractor failed.
+#[cfg(not(test))]
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+#[cfg(test)]
+const RETRY_DELAY: Duration = Duration::from_millis(10);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(600);
+
+/// An entry of the regular queue.
+#[derive(Clone)]
+struct Title {
+    track: QueuedTrack,
+    request: Option<Request>,
+    /// Put back at the front to play again, e.g. by the track loop. It goes ahead of the fair
+    /// queue's order, which would otherwise put it behind everyone since its requester just had
+    /// a turn.
+    replay: bool,
+}
+
+impl Title {
+    fn new(track: QueuedTrack, request: Option<Request>) -> Self {
+        Title {
+            track,
+            request,
+            replay: false,
+        }
+    }
+}
+
+fn requester(title: &Title) -> Option<UserId> {
+    title.request.map(|request| request.user_id)
+}
+
+/// When each requester's entry was last played in the fair queue, counted in turns.
+#[derive(Default)]
+struct Turns {
+    turn: usize,
+    last_turn: HashMap<Option<UserId>, usize>,
+}
 
+/// A queue entry as listed to users.
+pub(crate) struct QueueEntry {
+    pub(crate) track: QueuedTrack,
+    pub(crate) request: Option<Request>,
+    pub(crate) from_autoplaylist: bool,
+}
+
+/// Handle to a guild's queues. Clones share the same queues, so a clone can be handed to
+/// background work without holding the guild's properties lock.
+#[derive(Clone)]
 pub(crate) struct Playlist {
+    /// The file `autoplaylist` was read from.
+    autoplaylist_path: Arc<RwLock<String>>,
     autoplaylist: Arc<RwLock<HashSet<String>>>,
     remaining_autoplaylist: Arc<RwLock<Vec<String>>>,
     regular_queue: Arc<RwLock<Vec<Title>>>,
-    autoplaylist_queue: Arc<RwLock<Vec<(String, String)>>>,
+    autoplaylist_queue: Arc<RwLock<Vec<TrackInfo>>>,
+    resolver: Arc<dyn TrackResolver>,
+    next_id: Arc<AtomicUsize>,
+    /// Plays the regular queue round-robin by requester instead of in order.
+    fair: Arc<AtomicBool>,
+    turns: Arc<RwLock<Turns>>,
+    /// Whether the autoplaylist queue was filled for the first time, see `start_autoplaylist`.
+    autoplaylist_started: Arc<AtomicBool>,
+    /// Wakes the guild's player whenever a track becomes ready to play.
+    events: Sender<PlayerMessage>,
 }
 
 impl Playlist {
-    pub(crate) fn new(path: &str) -> Self {
-        let autoplaylist = Arc::new(RwLock::new(HashSet::<String>::new()));
+    /// Creates the playlist, starting from the queues saved before a restart. Saved autoplaylist
+    /// progress only keeps entries that are still in the autoplaylist file.
+    pub(crate) fn new(
+        path: &str,
+        resolver: Arc<dyn TrackResolver>,
+        state: PlaylistState,
+        events: Sender<PlayerMessage>,
+    ) -> Self {
+        let autoplaylist = Arc::new(RwLock::new(Playlist::read_autoplaylist(path)));
+        let remaining_autoplaylist = Arc::new(RwLock::new(
+            state
+                .remaining_autoplaylist
+                .into_iter()
+                .filter(|url| autoplaylist.read().contains(url))
+                .collect::<Vec<String>>(),
+        ));
+        Playlist::fill_remaining_autoplaylist(
+            &autoplaylist.read(),
+            &mut remaining_autoplaylist.write(),
+        );
 
-        if !path.is_empty() {
-            if let Ok(file) = File::open(path) {
-                let reader = BufReader::new(file);
-                for line in reader.lines() {
-                    if let Ok(line) = line {
-                        if line.starts_with("http") {
-                            autoplaylist.write().insert(line);
-                        }
-                    }
-                }
-            }
+        Playlist {
+            autoplaylist_path: Arc::new(RwLock::new(path.to_string())),
+            autoplaylist,
+            remaining_autoplaylist,
+            regular_queue: Arc::new(RwLock::new(
+                state
+                    .queue
+                    .into_iter()
+                    .map(|(track, request)| Title::new(QueuedTrack::Resolved(track), request))
+                    .collect(),
+            )),
+            autoplaylist_queue: Arc::new(RwLock::new(state.autoplaylist_queue)),
+            resolver,
+            next_id: Arc::new(AtomicUsize::new(0)),
+            fair: Arc::new(AtomicBool::new(false)),
+            turns: Arc::new(RwLock::new(Turns::default())),
+            autoplaylist_started: Arc::new(AtomicBool::new(false)),
+            events,
         }
+    }
 
-        let remaining_autoplaylist = Arc::new(RwLock::new(Vec::<String>::new()));
-        Playlist::fill_remaining_autoplaylist(
-            &*autoplaylist.read(),
-            &mut *remaining_autoplaylist.write(),
-        );
+    /// Resolves the first few autoplaylist entries in the background, so the first track
+    /// doesn't wait on youtube-dl. Only the first call does anything; it is left until the bot
+    /// joins, so guilds that never play don't run any lookups.
+    pub(crate) fn start_autoplaylist(&self) {
+        if self.autoplaylist_started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        self.fill_autoplaylist_queue();
+    }
 
-        let autoplaylist_queue = Arc::new(RwLock::new(Vec::<(String, String)>::new()));
-        let mut remaining_count = cmp::min(5, autoplaylist.read().len());
-        let mut rng = rand::thread_rng();
-        while remaining_count > 0 {
-            let mut threads = Vec::<(usize, String, JoinHandle<Option<String>>)>::new();
-            let autoplaylist_length = remaining_autoplaylist.read().len();
-            for _ in 0..remaining_count {
-                let random_index = rng.gen_range(usize::min_value(), autoplaylist_length);
-                let url = remaining_autoplaylist.read()[random_index].clone();
-                threads.push((
-                    random_index,
-                    url.clone(),
-                    thread::spawn(move || match ytdl::get_title(&url) {
-                        Ok(title) => Some(title),
-                        Err(_) => None,
-                    }),
-                ));
+    fn fill_autoplaylist_queue(&self) {
+        let queued = self.autoplaylist_queue.read().len();
+        for _ in queued..cmp::min(5, self.autoplaylist.read().len()) {
+            self.refill_autoplaylist_queue();
+        }
+    }
+
+    /// Switches to the autoplaylist at `path` unless it is the one in use already. Tracks
+    /// resolved from the previous autoplaylist are dropped.
+    pub(crate) fn set_autoplaylist(&self, path: &str) {
+        {
+            let mut autoplaylist_path = self.autoplaylist_path.write();
+            if *autoplaylist_path == path {
+                return;
             }
-            for (index, url, thread) in threads {
-                remaining_autoplaylist.write().remove(index);
-                match thread.join().unwrap() {
-                    Some(title) => {
-                        autoplaylist_queue.write().push((title, url));
-                        remaining_count -= 1;
-                    }
-                    None => {
-                        autoplaylist.write().remove(&url);
+            *autoplaylist_path = path.to_string();
+        }
+
+        let autoplaylist = Playlist::read_autoplaylist(path);
+        {
+            let mut remaining_autoplaylist = self.remaining_autoplaylist.write();
+            remaining_autoplaylist.clear();
+            Playlist::fill_remaining_autoplaylist(&autoplaylist, &mut remaining_autoplaylist);
+        }
+        *self.autoplaylist.write() = autoplaylist;
+        self.autoplaylist_queue.write().clear();
+        if self.autoplaylist_started.load(Ordering::SeqCst) {
+            self.fill_autoplaylist_queue();
+        }
+    }
+
+    /// The URLs listed in the autoplaylist file, none if there is no such file.
+    fn read_autoplaylist(path: &str) -> HashSet<String> {
+        let mut autoplaylist = HashSet::new();
+        if !path.is_empty() {
+            if let Ok(file) = File::open(path) {
+                let reader = BufReader::new(file);
+                for line in reader.lines().map_while(Result::ok) {
+                    if line.starts_with("http") {
+                        autoplaylist.insert(line);
                     }
                 }
             }
         }
-
-        Playlist {
-            autoplaylist,
-            remaining_autoplaylist: remaining_autoplaylist.clone(),
-            regular_queue: Arc::new(RwLock::new(Vec::new())),
-            autoplaylist_queue,
-        }
+        autoplaylist
     }
 
     fn fill_remaining_autoplaylist(
@@ -95,77 +226,646 @@ impl Playlist {
         }
     }
 
-    pub(crate) fn get_queue(&self) -> Vec<String> {
+    pub(crate) fn set_fair(&self, fair: bool) {
+        self.fair.store(fair, Ordering::SeqCst);
+    }
+
+    pub(crate) fn is_fair(&self) -> bool {
+        self.fair.load(Ordering::SeqCst)
+    }
+
+    /// Indices of the regular queue in the order they will play. In the fair queue replayed
+    /// entries go first, then the requester whose entry played longest ago, ties go to whoever
+    /// queued first.
+    fn play_order(&self, regular_queue: &[Title]) -> Vec<usize> {
+        if !self.is_fair() {
+            return (0..regular_queue.len()).collect();
+        }
+
+        let (mut turn, mut last_turn) = {
+            let turns = self.turns.read();
+            (turns.turn, turns.last_turn.clone())
+        };
+        let mut remaining: Vec<usize> = (0..regular_queue.len()).collect();
+        let mut order = Vec::with_capacity(remaining.len());
+        while let Some(position) = (0..remaining.len()).min_by_key(|&position| {
+            let title = &regular_queue[remaining[position]];
+            let last_turn = last_turn.get(&requester(title)).copied().unwrap_or(0);
+            (!title.replay, last_turn, position)
+        }) {
+            let index = remaining.remove(position);
+            turn += 1;
+            last_turn.insert(requester(&regular_queue[index]), turn);
+            order.push(index);
+        }
+        order
+    }
+
+    /// Lists the regular queue in play order followed by the autoplaylist queue.
+    pub(crate) fn get_queue(&self) -> Vec<QueueEntry> {
         let regular_queue = self.regular_queue.read();
         let autoplaylist_queue = self.autoplaylist_queue.read();
-        let mut queue = Vec::<String>::new();
-        for (title, _, _) in &*regular_queue {
-            queue.push(title.clone());
+        let mut queue = Vec::<QueueEntry>::new();
+        for index in self.play_order(&regular_queue) {
+            let title = &regular_queue[index];
+            queue.push(QueueEntry {
+                track: title.track.clone(),
+                request: title.request,
+                from_autoplaylist: false,
+            });
         }
-        for (title, _) in &*autoplaylist_queue {
-            queue.push(title.clone());
+        for track in &*autoplaylist_queue {
+            queue.push(QueueEntry {
+                track: QueuedTrack::Resolved(track.clone()),
+                request: None,
+                from_autoplaylist: true,
+            });
         }
         queue
     }
 
-    pub(crate) fn poll(&mut self) -> Option<(String, String, Option<ChannelId>)> {
+    /// Number of requested tracks waiting to be played, including ones still being resolved.
+    /// Autoplaylist entries are not counted.
+    pub(crate) fn len(&self) -> usize {
+        self.regular_queue.read().len()
+    }
+
+    /// Number of entries of the regular queue the user requested.
+    pub(crate) fn requested_by(&self, user_id: UserId) -> usize {
+        self.regular_queue
+            .read()
+            .iter()
+            .filter(|title| requester(title) == Some(user_id))
+            .count()
+    }
+
+    /// Removes the entry at `index` of the regular queue, in play order, if `permitted` allows
+    /// it for the entry's request. Returns `None` when there is no such entry, and the entry
+    /// either way.
+    pub(crate) fn remove_if<F>(
+        &self,
+        index: usize,
+        permitted: F,
+    ) -> Option<Result<QueuedTrack, QueuedTrack>>
+    where
+        F: FnOnce(Option<Request>) -> bool,
+    {
+        let mut regular_queue = self.regular_queue.write();
+        let index = *self.play_order(&regular_queue).get(index)?;
+        let title = regular_queue[index].clone();
+        if permitted(title.request) {
+            regular_queue.remove(index);
+            Some(Ok(title.track))
+        } else {
+            Some(Err(title.track))
+        }
+    }
+
+    /// Moves the regular queue entry at `from` to `to`. Returns the moved entry, or `None` if
+    /// either position is out of range. The fair queue decides the order by itself, so entries
+    /// should only be moved while it is off.
+    pub(crate) fn move_entry(&self, from: usize, to: usize) -> Option<QueuedTrack> {
+        let mut regular_queue = self.regular_queue.write();
+        if from >= regular_queue.len() || to >= regular_queue.len() {
+            return None;
+        }
+        let entry = regular_queue.remove(from);
+        let track = entry.track.clone();
+        regular_queue.insert(to, entry);
+        Some(track)
+    }
+
+    /// Empties the regular queue and returns how many entries it had. Entries still being
+    /// resolved are dropped once their lookup finishes.
+    pub(crate) fn clear(&self) -> usize {
+        let mut regular_queue = self.regular_queue.write();
+        let count = regular_queue.len();
+        regular_queue.clear();
+        count
+    }
+
+    pub(crate) fn shuffle(&self) {
+        self.regular_queue.write().shuffle(&mut rand::thread_rng());
+    }
+
+    /// Drops the entries playing before `index` so the entry at `index` plays next. Returns how
+    /// many entries were dropped, or `None` if `index` is out of range.
+    pub(crate) fn skip_to(&self, index: usize) -> Option<usize> {
+        let mut regular_queue = self.regular_queue.write();
+        if index >= regular_queue.len() {
+            return None;
+        }
+        let mut dropped = self.play_order(&regular_queue)[..index].to_vec();
+        dropped.sort_unstable();
+        for index in dropped.into_iter().rev() {
+            regular_queue.remove(index);
+        }
+        Some(index)
+    }
+
+    /// Copies the queues for persisting. Tracks that are still being resolved are left out.
+    pub(crate) fn snapshot(&self) -> PlaylistState {
+        PlaylistState {
+            queue: self
+                .regular_queue
+                .read()
+                .iter()
+                .filter_map(|title| match &title.track {
+                    QueuedTrack::Resolved(track) => Some((track.clone(), title.request)),
+                    QueuedTrack::Resolving(..) => None,
+                })
+                .collect(),
+            remaining_autoplaylist: self.remaining_autoplaylist.read().clone(),
+            autoplaylist_queue: self.autoplaylist_queue.read().clone(),
+        }
+    }
+
+    /// Takes the next track to play. Returns `None` while the next entry of the regular queue is
+    /// still being resolved so it keeps its place instead of being overtaken by the autoplaylist.
+    /// The flag tells whether the track came from the autoplaylist.
+    pub(crate) fn poll(&mut self) -> Option<(TrackInfo, Option<Request>, bool)> {
         {
             let mut regular_queue = self.regular_queue.write();
-            if !regular_queue.is_empty() {
-                return Some(regular_queue.remove(0));
+            if let Some(&index) = self.play_order(&regular_queue).first() {
+                if let QueuedTrack::Resolving(..) = regular_queue[index].track {
+                    return None;
+                }
+                let title = regular_queue.remove(index);
+                let mut turns = self.turns.write();
+                turns.turn += 1;
+                let turn = turns.turn;
+                turns.last_turn.insert(requester(&title), turn);
+                if let QueuedTrack::Resolved(track) = title.track {
+                    return Some((track, title.request, false));
+                }
             }
         }
 
         if !self.autoplaylist.read().is_empty() {
             let next = {
                 let mut autoplaylist_queue = self.autoplaylist_queue.write();
-                let removed = autoplaylist_queue.remove(0);
-                Some((removed.0, removed.1, None))
+                if autoplaylist_queue.is_empty() {
+                    return None;
+                }
+                Some((autoplaylist_queue.remove(0), None, true))
             };
 
-            let autoplaylist_clone = self.autoplaylist.clone();
-            let remaining_autoplaylist_clone = self.remaining_autoplaylist.clone();
-            let autoplaylist_queue_clone = self.autoplaylist_queue.clone();
-            thread::spawn(move || loop {
+            self.refill_autoplaylist_queue();
+            return next;
+        }
+
+        None
+    }
+
+    /// The track `poll` would take next, without taking it.
+    pub(crate) fn peek(&self) -> Option<TrackInfo> {
+        let regular_queue = self.regular_queue.read();
+        if let Some(&index) = self.play_order(&regular_queue).first() {
+            return match &regular_queue[index].track {
+                QueuedTrack::Resolved(track) => Some(track.clone()),
+                QueuedTrack::Resolving(..) => None,
+            };
+        }
+        if self.autoplaylist.read().is_empty() {
+            return None;
+        }
+        self.autoplaylist_queue.read().first().cloned()
+    }
+
+    /// Resolves a random autoplaylist entry on a background thread and appends it to the
+    /// autoplaylist queue. Entries whose URL can't be resolved are dropped from the autoplaylist.
+    /// When the extractor itself fails, the entry is put back and the lookup retried later, with
+    /// the wait doubling up to `MAX_RETRY_DELAY`.
+    fn refill_autoplaylist_queue(&self) {
+        let autoplaylist_clone = self.autoplaylist.clone();
+        let remaining_autoplaylist_clone = self.remaining_autoplaylist.clone();
+        let autoplaylist_queue_clone = self.autoplaylist_queue.clone();
+        let resolver = self.resolver.clone();
+        let events = self.events.clone();
+        let mut retry_delay = RETRY_DELAY;
+        thread::spawn(move || loop {
+            let url = {
                 let mut rng = rand::thread_rng();
                 let mut remaining_autoplaylist = remaining_autoplaylist_clone.write();
-                let mut autoplaylist_queue = autoplaylist_queue_clone.write();
                 if remaining_autoplaylist.is_empty() {
                     Playlist::fill_remaining_autoplaylist(
-                        &*autoplaylist_clone.read(),
+                        &autoplaylist_clone.read(),
                         &mut remaining_autoplaylist,
                     );
                 }
-                let random_index = rng.gen_range(usize::min_value(), remaining_autoplaylist.len());
-                let url = &remaining_autoplaylist.remove(random_index);
-                match ytdl::get_title(url) {
-                    Ok(title) => {
-                        autoplaylist_queue.push((title, url.clone()));
-                        return;
+                if remaining_autoplaylist.is_empty() {
+                    return;
+                }
+                let random_index = rng.gen_range(0, remaining_autoplaylist.len());
+                remaining_autoplaylist.remove(random_index)
+            };
+            match resolver.resolve(&url) {
+                Ok(track) => {
+                    autoplaylist_queue_clone.write().push(track);
+                    let _ = events.send(PlayerMessage::Update);
+                    return;
+                }
+                Err(err) if err.is_url_specific() => {
+                    autoplaylist_clone.write().remove(&url);
+                }
+                Err(err) => {
+                    warn!(
+                        "Couldn't resolve autoplaylist entry {}: {}, retrying in {}s",
+                        url,
+                        err,
+                        retry_delay.as_secs()
+                    );
+                    remaining_autoplaylist_clone.write().push(url);
+                    thread::sleep(retry_delay);
+                    retry_delay = cmp::min(retry_delay * 2, MAX_RETRY_DELAY);
+                }
+            }
+        });
+    }
+
+    /// Queues `url` right away under a placeholder showing `label` and resolves it in the
+    /// background. The placeholder is replaced by the resolved track, or dropped if resolution
+    /// fails or `accept` rejects the track, before `on_resolved` is called with the outcome. If
+    /// the placeholder was removed from the queue meanwhile, the track is dropped as well.
+    pub(crate) fn push<A, F>(
+        &self,
+        url: String,
+        label: String,
+        request: Request,
+        accept: A,
+        on_resolved: F,
+    ) where
+        A: FnOnce(&TrackInfo) -> Result<(), String> + Send + 'static,
+        F: FnOnce(Result<TrackInfo, String>) + Send + 'static,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.regular_queue
+            .write()
+            .push(Title::new(QueuedTrack::Resolving(id, label), Some(request)));
+
+        let regular_queue = self.regular_queue.clone();
+        let resolver = self.resolver.clone();
+        let events = self.events.clone();
+        thread::spawn(move || {
+            let result = resolver
+                .resolve(&url)
+                .map_err(|err: YtdlError| err.to_string())
+                .and_then(|track| accept(&track).map(|_| track));
+            let result = {
+                let mut regular_queue = regular_queue.write();
+                let position = regular_queue.iter().position(|title| match title.track {
+                    QueuedTrack::Resolving(pending_id, _) => pending_id == id,
+                    QueuedTrack::Resolved(_) => false,
+                });
+                match (position, result) {
+                    (Some(position), Ok(track)) => {
+                        regular_queue[position].track = QueuedTrack::Resolved(track.clone());
+                        Ok(track)
                     }
-                    Err(_) => {
-                        autoplaylist_clone.write().remove(url);
+                    (Some(position), Err(err)) => {
+                        regular_queue.remove(position);
+                        Err(err)
                     }
+                    (None, Ok(_)) => {
+                        Err("it was removed from the queue while it was looked up".to_string())
+                    }
+                    (None, Err(err)) => Err(err),
                 }
-            });
-            return next;
+            };
+            // Either way the entry no longer holds up the ones behind it.
+            let _ = events.send(PlayerMessage::Update);
+            on_resolved(result);
+        });
+    }
+
+    /// Puts a track that was already played back into the regular queue, at the front to play
+    /// it again next, even in the fair queue, or at the end.
+    pub(crate) fn requeue(&self, track: TrackInfo, request: Option<Request>, at_front: bool) {
+        let mut regular_queue = self.regular_queue.write();
+        let mut entry = Title::new(QueuedTrack::Resolved(track), request);
+        if at_front {
+            entry.replay = true;
+            regular_queue.insert(0, entry);
+        } else {
+            regular_queue.push(entry);
         }
+    }
 
-        None
+    /// Queues an already resolved track, e.g. an entry of an expanded playlist.
+    pub(crate) fn push_track(&self, track: TrackInfo, request: Request) {
+        self.regular_queue
+            .write()
+            .push(Title::new(QueuedTrack::Resolved(track), Some(request)));
+        let _ = self.events.send(PlayerMessage::Update);
     }
+}
 
-    pub(crate) fn push(
-        &mut self,
-        url: String,
-        channel_id: Option<ChannelId>,
-    ) -> Result<String, ()> {
-        let mut regular_queue = self.regular_queue.write();
-        match ytdl::get_title(&url) {
-            Ok(title) => {
-                regular_queue.push((title.clone(), url, channel_id));
-                Ok(title)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::music::resolver::fake::FakeResolver;
+    use std::env;
+    use std::fs;
+    use std::sync::mpsc;
+    use std::sync::mpsc::Receiver;
+    use std::time::Duration;
+
+    fn track(url: &str) -> TrackInfo {
+        TrackInfo {
+            title: url.to_string(),
+            duration: Some(Duration::from_secs(60)),
+            webpage_url: url.to_string(),
+            uploader: None,
+            thumbnail: None,
+            extractor: "fake".to_string(),
+            is_live: false,
+        }
+    }
+
+    fn request(user_id: u64) -> Request {
+        Request {
+            user_id: UserId(user_id),
+            channel_id: ChannelId(1),
+            requested_at: SystemTime::now(),
+            message_id: None,
+        }
+    }
+
+    fn playlist(resolver: &Arc<FakeResolver>) -> (Playlist, Receiver<PlayerMessage>) {
+        let (events, receiver) = mpsc::channel();
+        let playlist = Playlist::new("", resolver.clone(), PlaylistState::default(), events);
+        (playlist, receiver)
+    }
+
+    /// Pushes `url` and returns a receiver for its outcome.
+    fn push(
+        playlist: &Playlist,
+        url: &str,
+        accept: Result<(), String>,
+    ) -> Receiver<Result<TrackInfo, String>> {
+        let (sender, receiver) = mpsc::channel();
+        playlist.push(
+            url.to_string(),
+            format!("<{}>", url),
+            request(1),
+            move |_| accept,
+            move |result| {
+                let _ = sender.send(result);
+            },
+        );
+        receiver
+    }
+
+    fn titles(playlist: &Playlist) -> Vec<String> {
+        playlist
+            .get_queue()
+            .into_iter()
+            .map(|entry| match entry.track {
+                QueuedTrack::Resolved(track) => track.title,
+                QueuedTrack::Resolving(_, label) => label,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn push_queues_a_placeholder_until_resolved() {
+        let resolver = Arc::new(FakeResolver::default());
+        resolver.insert("a", track("a"));
+        let release = resolver.hold();
+        let (playlist, _events) = playlist(&resolver);
+
+        let resolved = push(&playlist, "a", Ok(()));
+        assert!(matches!(
+            &playlist.get_queue()[0].track,
+            QueuedTrack::Resolving(_, label) if label == "<a>"
+        ));
+
+        release.send(()).unwrap();
+        let result = resolved.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(result.unwrap().title, "a");
+        assert_eq!(titles(&playlist), vec!["a"]);
+    }
+
+    #[test]
+    fn push_drops_tracks_removed_while_resolving() {
+        let resolver = Arc::new(FakeResolver::default());
+        resolver.insert("a", track("a"));
+        let release = resolver.hold();
+        let (playlist, _events) = playlist(&resolver);
+
+        let resolved = push(&playlist, "a", Ok(()));
+        assert_eq!(playlist.clear(), 1);
+        release.send(()).unwrap();
+        assert!(resolved
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .is_err());
+        assert_eq!(playlist.len(), 0);
+    }
+
+    #[test]
+    fn push_drops_unresolvable_and_rejected_tracks() {
+        let resolver = Arc::new(FakeResolver::default());
+        resolver.insert("a", track("a"));
+        let (playlist, _events) = playlist(&resolver);
+
+        let unknown = push(&playlist, "unknown", Ok(()));
+        assert!(unknown
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .is_err());
+        let rejected = push(&playlist, "a", Err("too long".to_string()));
+        assert_eq!(
+            rejected.recv_timeout(Duration::from_secs(5)).unwrap().err(),
+            Some("too long".to_string())
+        );
+        assert_eq!(playlist.len(), 0);
+    }
+
+    #[test]
+    fn resolving_head_holds_up_the_autoplaylist() {
+        let path = env::temp_dir().join(format!("autoplaylist-test-{}", std::process::id()));
+        fs::write(&path, "http://auto\n").unwrap();
+        let resolver = Arc::new(FakeResolver::default());
+        let (events, _events) = mpsc::channel();
+        let state = PlaylistState {
+            autoplaylist_queue: vec![track("http://auto")],
+            ..PlaylistState::default()
+        };
+        let mut playlist = Playlist::new(path.to_str().unwrap(), resolver.clone(), state, events);
+        fs::remove_file(&path).unwrap();
+
+        playlist.regular_queue.write().push(Title::new(
+            QueuedTrack::Resolving(0, "<a>".to_string()),
+            Some(request(1)),
+        ));
+        assert!(playlist.peek().is_none());
+        assert!(playlist.poll().is_none());
+
+        playlist.regular_queue.write().clear();
+        assert_eq!(playlist.peek().unwrap().title, "http://auto");
+        let (track, request, from_autoplaylist) = playlist.poll().unwrap();
+        assert_eq!(track.title, "http://auto");
+        assert!(request.is_none());
+        assert!(from_autoplaylist);
+    }
+
+    #[test]
+    fn remove_if_only_removes_permitted_entries() {
+        let resolver = Arc::new(FakeResolver::default());
+        let (playlist, _events) = playlist(&resolver);
+        playlist.push_track(track("a"), request(1));
+        playlist.push_track(track("b"), request(2));
+
+        assert!(matches!(playlist.remove_if(1, |_| false), Some(Err(_))));
+        assert_eq!(titles(&playlist), vec!["a", "b"]);
+        let removed = playlist.remove_if(1, |request| {
+            request.map(|request| request.user_id) == Some(UserId(2))
+        });
+        assert!(matches!(
+            removed,
+            Some(Ok(QueuedTrack::Resolved(track))) if track.title == "b"
+        ));
+        assert!(playlist.remove_if(1, |_| true).is_none());
+        assert_eq!(titles(&playlist), vec!["a"]);
+    }
+
+    #[test]
+    fn skip_to_drops_the_entries_before() {
+        let resolver = Arc::new(FakeResolver::default());
+        let (mut playlist, _events) = playlist(&resolver);
+        for url in &["a", "b", "c"] {
+            playlist.push_track(track(url), request(1));
+        }
+
+        assert_eq!(playlist.skip_to(3), None);
+        assert_eq!(playlist.skip_to(2), Some(2));
+        assert_eq!(playlist.poll().unwrap().0.title, "c");
+        assert!(playlist.poll().is_none());
+    }
+
+    #[test]
+    fn requeue_puts_tracks_at_the_front_or_the_end() {
+        let resolver = Arc::new(FakeResolver::default());
+        let (playlist, _events) = playlist(&resolver);
+        playlist.push_track(track("a"), request(1));
+        playlist.push_track(track("b"), request(1));
+
+        playlist.requeue(track("front"), Some(request(1)), true);
+        playlist.requeue(track("end"), None, false);
+        assert_eq!(titles(&playlist), vec!["front", "a", "b", "end"]);
+    }
+
+    #[test]
+    fn fair_queue_interleaves_requesters() {
+        let resolver = Arc::new(FakeResolver::default());
+        let (mut playlist, _events) = playlist(&resolver);
+        playlist.set_fair(true);
+        for (url, user_id) in &[("a1", 1), ("a2", 1), ("a3", 1), ("b1", 2), ("c1", 3)] {
+            playlist.push_track(track(url), request(*user_id));
+        }
+
+        assert_eq!(titles(&playlist), vec!["a1", "b1", "c1", "a2", "a3"]);
+        assert_eq!(playlist.poll().unwrap().0.title, "a1");
+        playlist.push_track(track("a4"), request(1));
+        assert_eq!(titles(&playlist), vec!["b1", "c1", "a2", "a3", "a4"]);
+    }
+
+    #[test]
+    fn fair_queue_replays_tracks_put_back_at_the_front() {
+        let resolver = Arc::new(FakeResolver::default());
+        let (mut playlist, _events) = playlist(&resolver);
+        playlist.set_fair(true);
+        playlist.push_track(track("a"), request(1));
+        playlist.push_track(track("b"), request(2));
+
+        let (track, request, _) = playlist.poll().unwrap();
+        assert_eq!(track.title, "a");
+        playlist.requeue(track, request, true);
+        assert_eq!(titles(&playlist), vec!["a", "b"]);
+        assert_eq!(playlist.peek().unwrap().title, "a");
+        assert_eq!(playlist.poll().unwrap().0.title, "a");
+        assert_eq!(playlist.poll().unwrap().0.title, "b");
+    }
+
+    #[test]
+    fn unresolvable_autoplaylist_entries_are_dropped() {
+        let path = env::temp_dir().join(format!("autoplaylist-gone-{}", std::process::id()));
+        fs::write(&path, "http://gone\n").unwrap();
+        let resolver = Arc::new(FakeResolver::default());
+        let (events, _events) = mpsc::channel();
+        let playlist = Playlist::new(
+            path.to_str().unwrap(),
+            resolver.clone(),
+            PlaylistState::default(),
+            events,
+        );
+        fs::remove_file(&path).unwrap();
+
+        assert!(!playlist.autoplaylist.read().is_empty());
+        playlist.start_autoplaylist();
+        for _ in 0..500 {
+            if playlist.autoplaylist.read().is_empty() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the entry wasn't dropped");
+    }
+
+    #[test]
+    fn set_autoplaylist_switches_lists() {
+        let path = |name: &str| {
+            env::temp_dir()
+                .join(format!("autoplaylist-{}-{}", name, std::process::id()))
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+        let (old, new) = (path("old"), path("new"));
+        fs::write(&old, "http://old\n").unwrap();
+        fs::write(&new, "http://new\n").unwrap();
+        let resolver = Arc::new(FakeResolver::default());
+        let (events, _events) = mpsc::channel();
+        let state = PlaylistState {
+            autoplaylist_queue: vec![track("http://old")],
+            ..PlaylistState::default()
+        };
+        let playlist = Playlist::new(&old, resolver.clone(), state, events);
+
+        playlist.set_autoplaylist(&new);
+        fs::remove_file(&old).unwrap();
+        fs::remove_file(&new).unwrap();
+        assert!(playlist.peek().is_none());
+        assert!(playlist.autoplaylist.read().contains("http://new"));
+        assert_eq!(*playlist.remaining_autoplaylist.read(), vec!["http://new"]);
+    }
+
+    #[test]
+    fn failed_autoplaylist_lookups_are_retried() {
+        let path = env::temp_dir().join(format!("autoplaylist-flaky-{}", std::process::id()));
+        fs::write(&path, "http://flaky\n").unwrap();
+        let resolver = Arc::new(FakeResolver::default());
+        resolver.insert("http://flaky", track("http://flaky"));
+        resolver.fail("http://flaky", 2);
+        let (events, _events) = mpsc::channel();
+        let playlist = Playlist::new(
+            path.to_str().unwrap(),
+            resolver.clone(),
+            PlaylistState::default(),
+            events,
+        );
+        fs::remove_file(&path).unwrap();
+
+        playlist.start_autoplaylist();
+        for _ in 0..500 {
+            if !playlist.autoplaylist_queue.read().is_empty() {
+                assert!(playlist.autoplaylist.read().contains("http://flaky"));
+                assert_eq!(playlist.peek().unwrap().title, "http://flaky");
+                return;
             }
-            Err(_) => Err(()),
+            thread::sleep(Duration::from_millis(10));
         }
+        panic!("the entry wasn't retried");
     }
 }
@@ -0,0 +1,107 @@
+use crate::music::track::TrackInfo;
+use crate::music::ytdl::YtdlError;
+
+/// Looks up metadata for a track URL.
+///
+/// `Playlist` only talks to youtube-dl through this trait so queue handling can be exercised
+/// with `fake::FakeResolver` instead of a real binary and network access. Streaming isn't part
+/// of it: the stream is the extractor piped into ffmpeg and is only ever handed to the voice
+/// connection, which the queue never touches, so the player calls `YoutubeDl::stream` directly.
+pub(crate) trait TrackResolver: Send + Sync {
+    fn resolve(&self, url: &str) -> Result<TrackInfo, YtdlError>;
+
+    /// Returns up to `count` results for a free text query, best match first.
+    fn search(&self, query: &str, count: usize) -> Result<Vec<TrackInfo>, YtdlError>;
+
+    /// Returns the playlist's title and at most `max_items` of its entries. Entries only carry
+    /// the metadata the playlist page provides, so durations may be missing.
+    fn expand_playlist(
+        &self,
+        url: &str,
+        max_items: usize,
+    ) -> Result<(String, Vec<TrackInfo>), YtdlError>;
+}
+
+#[cfg(test)]
+pub(crate) mod fake {
+    use super::TrackResolver;
+    use crate::music::track::TrackInfo;
+    use crate::music::ytdl::YtdlError;
+    use serenity::prelude::{Mutex, RwLock};
+    use std::collections::HashMap;
+    use std::sync::mpsc;
+    use std::sync::mpsc::{Receiver, Sender};
+
+    #[derive(Default)]
+    pub(crate) struct FakeResolver {
+        tracks: RwLock<HashMap<String, TrackInfo>>,
+        held: Mutex<Option<Receiver<()>>>,
+        failures: Mutex<HashMap<String, usize>>,
+    }
+
+    impl FakeResolver {
+        pub(crate) fn insert(&self, url: &str, track: TrackInfo) {
+            self.tracks.write().insert(url.to_string(), track);
+        }
+
+        /// Makes the next `times` lookups of `url` fail like the extractor does when it can't
+        /// reach the site.
+        pub(crate) fn fail(&self, url: &str, times: usize) {
+            self.failures.lock().insert(url.to_string(), times);
+        }
+
+        /// Makes every later lookup wait for a message on the returned sender, or for it to be
+        /// dropped, so tests can look at entries that are still being resolved.
+        pub(crate) fn hold(&self) -> Sender<()> {
+            let (sender, receiver) = mpsc::channel();
+            *self.held.lock() = Some(receiver);
+            sender
+        }
+    }
+
+    impl TrackResolver for FakeResolver {
+        fn resolve(&self, url: &str) -> Result<TrackInfo, YtdlError> {
+            if let Some(held) = &*self.held.lock() {
+                let _ = held.recv();
+            }
+            if let Some(times) = self
+                .failures
+                .lock()
+                .get_mut(url)
+                .filter(|times| **times > 0)
+            {
+                *times -= 1;
+                return Err(YtdlError::Failed(
+                    Some(1),
+                    "Unable to download webpage".to_string(),
+                ));
+            }
+            self.tracks
+                .read()
+                .get(url)
+                .cloned()
+                .ok_or(YtdlError::UnsupportedUrl)
+        }
+
+        fn search(&self, query: &str, count: usize) -> Result<Vec<TrackInfo>, YtdlError> {
+            let query = query.to_lowercase();
+            Ok(self
+                .tracks
+                .read()
+                .values()
+                .filter(|track| track.title.to_lowercase().contains(&query))
+                .take(count)
+                .cloned()
+                .collect())
+        }
+
+        /// The fake knows no playlists.
+        fn expand_playlist(
+            &self,
+            _: &str,
+            _: usize,
+        ) -> Result<(String, Vec<TrackInfo>), YtdlError> {
+            Err(YtdlError::UnsupportedUrl)
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use crate::configuration::write_json;
+use crate::music::playlist::Request;
+use crate::music::track::TrackInfo;
+use serde_json::{json, Map, Value};
+use serenity::model::id::{ChannelId, GuildId, MessageId, UserId};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Queue contents of a guild that survive a restart. The track that was playing is stored as the
+/// head of `queue` so it starts over once the bot is back.
+#[derive(Default)]
+pub(crate) struct PlaylistState {
+    pub(crate) queue: Vec<(TrackInfo, Option<Request>)>,
+    pub(crate) remaining_autoplaylist: Vec<String>,
+    pub(crate) autoplaylist_queue: Vec<TrackInfo>,
+}
+
+#[derive(Default)]
+pub(crate) struct GuildState {
+    pub(crate) voice_channel_id: Option<ChannelId>,
+    pub(crate) playlist: PlaylistState,
+}
+
+impl GuildState {
+    fn to_json(&self) -> Value {
+        let queue: Vec<Value> = self
+            .playlist
+            .queue
+            .iter()
+            .map(|(track, request)| {
+                json!({
+                    "track": track.to_json(),
+                    "user_id": request.map(|request| request.user_id.0),
+                    "channel_id": request.map(|request| request.channel_id.0),
+                    "requested_at": request.and_then(|request| {
+                        let since_epoch = request.requested_at.duration_since(UNIX_EPOCH).ok()?;
+                        Some(since_epoch.as_secs())
+                    }),
+                    "message_id": request.and_then(|request| request.message_id).map(|id| id.0),
+                })
+            })
+            .collect();
+        let autoplaylist_queue: Vec<Value> = self
+            .playlist
+            .autoplaylist_queue
+            .iter()
+            .map(TrackInfo::to_json)
+            .collect();
+        json!({
+            "voice_channel_id": self.voice_channel_id.map(|id| id.0),
+            "queue": queue,
+            "remaining_autoplaylist": self.playlist.remaining_autoplaylist,
+            "autoplaylist_queue": autoplaylist_queue,
+        })
+    }
+
+    fn from_json(value: &Value) -> Self {
+        let array = |key: &str| value[key].as_array().cloned().unwrap_or_default();
+        GuildState {
+            voice_channel_id: value["voice_channel_id"].as_u64().map(ChannelId),
+            playlist: PlaylistState {
+                queue: array("queue")
+                    .iter()
+                    .filter_map(|entry| {
+                        let track = TrackInfo::from_json(&entry["track"], "")?;
+                        let request =
+                            match (entry["user_id"].as_u64(), entry["channel_id"].as_u64()) {
+                                (Some(user_id), Some(channel_id)) => Some(Request {
+                                    user_id: UserId(user_id),
+                                    channel_id: ChannelId(channel_id),
+                                    requested_at: entry["requested_at"]
+                                        .as_u64()
+                                        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+                                        .unwrap_or_else(SystemTime::now),
+                                    message_id: entry["message_id"].as_u64().map(MessageId),
+                                }),
+                                _ => None,
+                            };
+                        Some((track, request))
+                    })
+                    .collect(),
+                remaining_autoplaylist: array("remaining_autoplaylist")
+                    .iter()
+                    .filter_map(|url| url.as_str().map(|url| url.to_string()))
+                    .collect(),
+                autoplaylist_queue: array("autoplaylist_queue")
+                    .iter()
+                    .filter_map(|track| TrackInfo::from_json(track, ""))
+                    .collect(),
+            },
+        }
+    }
+}
+
+/// Reads the saved guild states. A missing or unreadable file yields no state.
+pub(crate) fn load(path: &str) -> HashMap<GuildId, GuildState> {
+    let value = match File::open(path) {
+        Ok(file) => match serde_json::from_reader::<File, Value>(file) {
+            Ok(value) => value,
+            Err(_) => {
+                warn!("Ignoring malformed state file {}", path);
+                return HashMap::new();
+            }
+        },
+        Err(_) => return HashMap::new(),
+    };
+
+    let mut states = HashMap::new();
+    if let Some(guilds) = value.as_object() {
+        for (guild_id, state) in guilds {
+            if let Ok(guild_id) = guild_id.parse::<u64>() {
+                states.insert(GuildId(guild_id), GuildState::from_json(state));
+            }
+        }
+    }
+    states
+}
+
+/// Writes the guild states to `path`, see `write_json`.
+pub(crate) fn save(path: &str, states: &HashMap<GuildId, GuildState>) -> io::Result<()> {
+    let mut guilds = Map::new();
+    for (guild_id, state) in states {
+        guilds.insert(guild_id.0.to_string(), state.to_json());
+    }
+
+    write_json(path, &Value::Object(guilds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn track(url: &str) -> TrackInfo {
+        TrackInfo {
+            title: url.to_string(),
+            duration: Some(Duration::from_secs(60)),
+            webpage_url: url.to_string(),
+            uploader: Some("uploader".to_string()),
+            thumbnail: None,
+            extractor: "fake".to_string(),
+            is_live: false,
+        }
+    }
+
+    fn titles(queue: &[(TrackInfo, Option<Request>)]) -> Vec<&str> {
+        queue
+            .iter()
+            .map(|(track, _)| track.title.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn guild_state_survives_a_round_trip() {
+        let requested_at = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let state = GuildState {
+            voice_channel_id: Some(ChannelId(7)),
+            playlist: PlaylistState {
+                queue: vec![
+                    (
+                        track("http://a"),
+                        Some(Request {
+                            user_id: UserId(1),
+                            channel_id: ChannelId(2),
+                            requested_at,
+                            message_id: Some(MessageId(3)),
+                        }),
+                    ),
+                    (track("http://b"), None),
+                ],
+                remaining_autoplaylist: vec!["http://c".to_string(), "http://d".to_string()],
+                autoplaylist_queue: vec![track("http://e")],
+            },
+        };
+
+        let restored = GuildState::from_json(&state.to_json());
+        assert_eq!(restored.voice_channel_id, Some(ChannelId(7)));
+        assert_eq!(
+            titles(&restored.playlist.queue),
+            vec!["http://a", "http://b"]
+        );
+        let (track, request) = &restored.playlist.queue[0];
+        assert_eq!(track.duration, Some(Duration::from_secs(60)));
+        assert_eq!(track.uploader.as_deref(), Some("uploader"));
+        let request = request.unwrap();
+        assert_eq!(request.user_id, UserId(1));
+        assert_eq!(request.channel_id, ChannelId(2));
+        assert_eq!(request.requested_at, requested_at);
+        assert_eq!(request.message_id, Some(MessageId(3)));
+        assert!(restored.playlist.queue[1].1.is_none());
+        assert_eq!(
+            restored.playlist.remaining_autoplaylist,
+            vec!["http://c", "http://d"]
+        );
+        assert_eq!(restored.playlist.autoplaylist_queue[0].title, "http://e");
+    }
+
+    #[test]
+    fn malformed_entries_are_skipped() {
+        let value = json!({
+            "voice_channel_id": "general",
+            "queue": [
+                {"track": {"webpage_url": "http://untitled"}, "user_id": 1, "channel_id": 2},
+                {"track": track("http://a").to_json(), "user_id": 1},
+            ],
+            "remaining_autoplaylist": ["http://b", 3],
+            "autoplaylist_queue": "none",
+        });
+
+        let restored = GuildState::from_json(&value);
+        assert_eq!(restored.voice_channel_id, None);
+        assert_eq!(titles(&restored.playlist.queue), vec!["http://a"]);
+        assert!(restored.playlist.queue[0].1.is_none());
+        assert_eq!(restored.playlist.remaining_autoplaylist, vec!["http://b"]);
+        assert!(restored.playlist.autoplaylist_queue.is_empty());
+    }
+
+    #[test]
+    fn saved_states_load_by_guild() {
+        let path = env::temp_dir().join(format!("state-test-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut states = HashMap::new();
+        states.insert(
+            GuildId(1),
+            GuildState {
+                voice_channel_id: Some(ChannelId(2)),
+                playlist: PlaylistState::default(),
+            },
+        );
+        save(path, &states).unwrap();
+
+        let loaded = load(path);
+        fs::remove_file(path).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[&GuildId(1)].voice_channel_id, Some(ChannelId(2)));
+        assert!(load(path).is_empty());
+    }
+}
@@ -0,0 +1,136 @@
+use serde_json::{json, Value};
+use std::time::Duration;
+
+#[derive(Clone)]
+pub(crate) struct TrackInfo {
+    pub(crate) title: String,
+    pub(crate) duration: Option<Duration>,
+    pub(crate) webpage_url: String,
+    pub(crate) uploader: Option<String>,
+    pub(crate) thumbnail: Option<String>,
+    pub(crate) extractor: String,
+    pub(crate) is_live: bool,
+}
+
+impl TrackInfo {
+    /// Builds the track from a youtube-dl `--dump-json` document, falling back to `url` when the
+    /// extractor does not report a webpage URL.
+    pub(crate) fn from_json(value: &Value, url: &str) -> Option<Self> {
+        let string = |key: &str| value[key].as_str().map(|s| s.to_string());
+        Some(TrackInfo {
+            title: string("title")?,
+            duration: value["duration"]
+                .as_f64()
+                .filter(|seconds| *seconds > 0.0)
+                .map(Duration::from_secs_f64),
+            webpage_url: string("webpage_url").unwrap_or_else(|| url.to_string()),
+            uploader: string("uploader"),
+            thumbnail: string("thumbnail"),
+            extractor: string("extractor").unwrap_or_default(),
+            is_live: value["is_live"].as_bool().unwrap_or(false),
+        })
+    }
+
+    /// Serializes the track with the same keys youtube-dl uses, so `from_json` reads it back.
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "title": self.title,
+            "duration": self.duration.map(|duration| duration.as_secs_f64()),
+            "webpage_url": self.webpage_url,
+            "uploader": self.uploader,
+            "thumbnail": self.thumbnail,
+            "extractor": self.extractor,
+            "is_live": self.is_live,
+        })
+    }
+
+    /// Title, uploader and duration as used in chat replies.
+    pub(crate) fn describe(&self) -> String {
+        match &self.uploader {
+            Some(uploader) => format!(
+                "\"{}\" by {} [{}]",
+                self.title,
+                uploader,
+                self.duration_text()
+            ),
+            None => format!("\"{}\" [{}]", self.title, self.duration_text()),
+        }
+    }
+
+    /// Duration as shown in the queue, `LIVE` for streams and `?` when unknown.
+    pub(crate) fn duration_text(&self) -> String {
+        if self.is_live {
+            return "LIVE".to_string();
+        }
+        match self.duration {
+            Some(duration) => format_duration(duration),
+            None => "?".to_string(),
+        }
+    }
+}
+
+/// Formats a duration as `m:ss`, or `h:mm:ss` once it reaches an hour.
+pub(crate) fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    if seconds >= 3600 {
+        format!(
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            seconds % 3600 / 60,
+            seconds % 60
+        )
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+/// Parses a position typed in chat: seconds (`83`), `m:ss` (`1:23`) or `h:mm:ss` (`1:02:03`).
+pub(crate) fn parse_timestamp(text: &str) -> Option<Duration> {
+    let mut seconds: u64 = 0;
+    let parts: Vec<&str> = text.trim().split(':').collect();
+    if parts.len() > 3 {
+        return None;
+    }
+    for part in parts {
+        seconds = seconds
+            .checked_mul(60)?
+            .checked_add(part.parse::<u64>().ok()?)?;
+    }
+    Some(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_timestamp_reads_seconds_minutes_and_hours() {
+        assert_eq!(parse_timestamp("83"), Some(Duration::from_secs(83)));
+        assert_eq!(parse_timestamp(" 1:23 "), Some(Duration::from_secs(83)));
+        assert_eq!(parse_timestamp("1:02:03"), Some(Duration::from_secs(3723)));
+        assert_eq!(parse_timestamp("0:90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_timestamp("1:02:03:04"), None);
+        assert_eq!(parse_timestamp("1:"), None);
+        assert_eq!(parse_timestamp("-1"), None);
+        assert_eq!(parse_timestamp("soon"), None);
+    }
+
+    #[test]
+    fn parse_timestamp_rejects_overflowing_positions() {
+        assert_eq!(parse_timestamp("999999999999999999:00"), None);
+        assert_eq!(parse_timestamp("18446744073709551615:00:00"), None);
+        assert_eq!(
+            parse_timestamp("18446744073709551615"),
+            Some(Duration::from_secs(u64::MAX))
+        );
+    }
+
+    #[test]
+    fn format_duration_switches_to_hours() {
+        assert_eq!(format_duration(Duration::from_secs(0)), "0:00");
+        assert_eq!(format_duration(Duration::from_secs(83)), "1:23");
+        assert_eq!(format_duration(Duration::from_secs(3599)), "59:59");
+        assert_eq!(format_duration(Duration::from_secs(3600)), "1:00:00");
+        assert_eq!(format_duration(Duration::from_millis(3_723_900)), "1:02:03");
+    }
+}
@@ -1,6 +1,24 @@
 use crate::configuration::ConfigLoader;
-use crate::configuration::CONF_AUTOPLAYLIST_PATH;
+use crate::configuration::{
+    CONF_ALONE_TIMEOUT, CONF_ANNOUNCE_CHANNEL, CONF_CROSSFADE, CONF_DEFAULT_VOLUME,
+    CONF_EXTRACTOR_ARGS, CONF_EXTRACTOR_PATH, CONF_EXTRACTOR_TIMEOUT, CONF_FAIR_QUEUE,
+    CONF_IDLE_TIMEOUT, CONF_MAX_QUEUE_LENGTH, CONF_MAX_TRACK_DURATION, CONF_MAX_USER_QUEUE_LENGTH,
+    CONF_PAUSE_WHEN_ALONE, CONF_PLAYLIST_MAX_ITEMS, CONF_PREFETCH, CONF_PREFIX,
+    CONF_REJOIN_VOICE_CHANNELS, CONF_SEARCH_PROVIDER, CONF_SEARCH_RESULTS, CONF_SEARCH_TIMEOUT,
+    CONF_SKIP_VOTE_PERCENT, CONF_STATE_PATH, CONF_STATE_SAVE_INTERVAL, CONF_STAY_CONNECTED,
+};
+use crate::music::player::{Enqueue, NotifyingSource, Player, PlayerMessage, StreamHandle};
 use crate::music::playlist;
+use crate::music::playlist::{QueuedTrack, Request};
+use crate::music::resolver::TrackResolver;
+use crate::music::state;
+use crate::music::state::{GuildState, PlaylistState};
+use crate::music::track::{format_duration, parse_timestamp, TrackInfo};
+use crate::music::ytdl;
+use crate::music::ytdl::YoutubeDl;
+use crate::permissions;
+use crate::permissions::{Role, DJ_CHECK, USER_CHECK};
+use serenity::cache::CacheRwLock;
 use serenity::client::bridge::voice::ClientVoiceManager;
 use serenity::client::Context;
 use serenity::framework::standard::macros::command;
@@ -11,42 +29,747 @@ use serenity::model::channel::ChannelType::Voice;
 use serenity::model::channel::{GuildChannel, Message};
 use serenity::model::id::ChannelId;
 use serenity::model::id::GuildId;
+use serenity::model::id::UserId;
 use serenity::prelude::Mutex;
 use serenity::prelude::RwLock;
 use serenity::prelude::ShareMap;
 use serenity::prelude::TypeMapKey;
-use serenity::voice;
-use serenity::voice::Handler;
-use serenity::voice::LockedAudio;
+use serenity::voice::{AudioSource, LockedAudio};
+use serenity::CacheAndHttp;
 use serenity::Client;
+use serenity::Error as SerenityError;
 use std::cmp;
-use std::collections::HashMap;
-use std::sync::atomic::AtomicBool;
-use std::sync::atomic::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
 use std::sync::Arc;
 use std::thread;
 use std::thread::JoinHandle;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
-struct VoiceManagerProperties {
+/// The track a guild is playing.
+struct NowPlaying {
+    track: TrackInfo,
+    request: Option<Request>,
+    from_autoplaylist: bool,
+    audio: LockedAudio,
+    /// Where in the track the stream started, `audio.position` counts from there.
+    offset: Duration,
+    stream: StreamHandle,
+    /// Listeners who voted to skip the track. Starts out empty for every track.
+    skip_votes: HashSet<UserId>,
+}
+
+impl NowPlaying {
+    fn elapsed(&self) -> Duration {
+        self.offset + self.audio.lock().position
+    }
+
+    /// Whether the track ended. A paused track hasn't.
+    fn ended(&self) -> bool {
+        self.stream.ended()
+    }
+
+    /// How much of the track is left, if that is known.
+    fn remaining(&self) -> Option<Duration> {
+        if self.track.is_live {
+            return None;
+        }
+        self.track
+            .duration
+            .map(|duration| duration.checked_sub(self.elapsed()).unwrap_or_default())
+    }
+}
+
+/// The stream of the track that plays next, started before the current track ends.
+struct Prefetched {
+    url: String,
+    /// `None` if the stream couldn't be started, it is retried once the track is played.
+    source: Option<Box<dyn AudioSource>>,
+}
+
+/// Discord rejects messages longer than this.
+const MAX_MESSAGE_LENGTH: usize = 2000;
+const QUEUE_PAGE_SIZE: usize = 10;
+/// Longest title shown in the queue, so a page of long titles still fits into one message.
+const MAX_TITLE_LENGTH: usize = 60;
+
+/// Highest volume in percent `volume` accepts, louder tracks start to clip badly.
+const MAX_VOLUME: u64 = 200;
+
+/// A guild's playback, owned by the guild's player thread.
+pub(crate) struct VoiceManagerProperties {
     playlist: playlist::Playlist,
-    current_audio: Option<(String, LockedAudio)>,
+    current_audio: Option<NowPlaying>,
+    search_results: HashMap<UserId, (Instant, Vec<TrackInfo>)>,
+    /// Volume in percent, applied to every track the guild plays.
+    volume: u64,
+    /// Set when playback was paused because everyone left the channel, so it resumes once
+    /// someone is back. Pausing or resuming by hand clears it.
+    auto_paused: bool,
+    loop_mode: LoopMode,
+    /// Since when nobody but bots is in the bot's voice channel.
+    alone_since: Option<Instant>,
+    /// Since when the bot is connected without anything to play.
+    idle_since: Option<Instant>,
+    prefetched: Option<Prefetched>,
+}
+
+#[derive(Clone, Copy)]
+enum LoopMode {
+    Off,
+    /// Plays the current track again once it ends.
+    Track,
+    /// Puts requested tracks back at the end of the queue once they end.
+    Queue,
+}
+
+impl LoopMode {
+    fn parse(name: &str) -> Option<LoopMode> {
+        match name {
+            "off" => Some(LoopMode::Off),
+            "track" => Some(LoopMode::Track),
+            "queue" => Some(LoopMode::Queue),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            LoopMode::Off => "off",
+            LoopMode::Track => "track",
+            LoopMode::Queue => "queue",
+        }
+    }
 }
 
 impl VoiceManagerProperties {
-    fn new(config_loader: &mut ConfigLoader) -> VoiceManagerProperties {
+    fn with_state(
+        config_loader: &mut ConfigLoader,
+        guild_id: GuildId,
+        resolver: Arc<dyn TrackResolver>,
+        events: Sender<PlayerMessage>,
+        state: PlaylistState,
+    ) -> VoiceManagerProperties {
         VoiceManagerProperties {
-            playlist: playlist::Playlist::new(&CONF_AUTOPLAYLIST_PATH.get_value(config_loader)),
+            playlist: playlist::Playlist::new(
+                &config_loader.autoplaylist_path(guild_id),
+                resolver,
+                state,
+                events,
+            ),
             current_audio: None,
+            search_results: HashMap::new(),
+            volume: CONF_DEFAULT_VOLUME.get_guild_value(config_loader, guild_id),
+            auto_paused: false,
+            loop_mode: LoopMode::Off,
+            alone_since: None,
+            idle_since: None,
+            prefetched: None,
+        }
+    }
+
+    /// When the bot should leave the guild's voice channel and why, if it is alone or has
+    /// nothing to play. Guilds that stay connected never time out.
+    fn leave_deadline(
+        &self,
+        config_loader: &mut ConfigLoader,
+        guild_id: GuildId,
+    ) -> Option<(Instant, String)> {
+        if CONF_STAY_CONNECTED.get_guild_value(config_loader, guild_id) {
+            return None;
+        }
+        let deadline = |since: Option<Instant>, timeout: Duration| {
+            since
+                .filter(|_| timeout > Duration::default())
+                .and_then(|since| since.checked_add(timeout))
+        };
+        let alone_timeout = CONF_ALONE_TIMEOUT.get_guild_value(config_loader, guild_id);
+        let idle_timeout = CONF_IDLE_TIMEOUT.get_guild_value(config_loader, guild_id);
+        let alone = deadline(self.alone_since, alone_timeout).map(|deadline| {
+            (
+                deadline,
+                format!("Left after {} alone", format_duration(alone_timeout)),
+            )
+        });
+        let idle = deadline(self.idle_since, idle_timeout).map(|deadline| {
+            (
+                deadline,
+                format!(
+                    "Left after {} without anything to play",
+                    format_duration(idle_timeout)
+                ),
+            )
+        });
+        alone
+            .into_iter()
+            .chain(idle)
+            .min_by_key(|(deadline, _)| *deadline)
+    }
+
+    /// Resets the guild's playback after leaving its voice channel. A requested track that was
+    /// still playing goes back to the front of the queue, so it plays again once the bot is back.
+    fn disconnected(&mut self) {
+        if let Some(now_playing) = self.current_audio.take() {
+            if !now_playing.from_autoplaylist && !now_playing.ended() {
+                self.playlist
+                    .requeue(now_playing.track, now_playing.request, true);
+            }
+        }
+        self.auto_paused = false;
+        self.alone_since = None;
+        self.idle_since = None;
+        self.prefetched = None;
+    }
+
+    /// The queues to persist, with the current track at the head so it starts over.
+    fn snapshot(&self) -> PlaylistState {
+        let mut playlist = self.playlist.snapshot();
+        if let Some(now_playing) = &self.current_audio {
+            playlist
+                .queue
+                .insert(0, (now_playing.track.clone(), now_playing.request));
+        }
+        playlist
+    }
+
+    /// Ends the current track and queues it again as the loop mode asks. A skipped track isn't
+    /// repeated in track mode, but stays in the rotation in queue mode.
+    fn finish_current(&mut self, skipped: bool) {
+        if let Some(now_playing) = self.current_audio.take() {
+            match self.loop_mode {
+                LoopMode::Track if !skipped => {
+                    self.playlist
+                        .requeue(now_playing.track, now_playing.request, true)
+                }
+                LoopMode::Queue if !now_playing.from_autoplaylist => {
+                    self.playlist
+                        .requeue(now_playing.track, now_playing.request, false)
+                }
+                _ => {}
+            }
         }
     }
 }
 
 struct VoiceManager {
     voice_manager: Arc<Mutex<ClientVoiceManager>>,
-    audio_monitor_active: Arc<AtomicBool>,
-    audio_monitor: Option<JoinHandle<()>>,
-    properties: Arc<Mutex<HashMap<GuildId, VoiceManagerProperties>>>,
+    youtube_dl: Arc<YoutubeDl>,
+    rejoin_channels: HashMap<GuildId, ChannelId>,
+    players: Players,
+    /// Stops and joins the thread saving the state periodically.
+    state_saver: Option<(Sender<()>, JoinHandle<()>)>,
+}
+
+/// Every guild the bot played in has a player thread that owns the guild's playback, so a slow
+/// lookup in one guild never holds up another. It sleeps until it gets a message or one of the
+/// guild's timeouts expires, and then brings the guild's playback up to date. Clones share the
+/// same players.
+#[derive(Clone)]
+struct Players {
+    players: Arc<Mutex<HashMap<GuildId, Player>>>,
+    voice_manager: Arc<Mutex<ClientVoiceManager>>,
+    youtube_dl: Arc<YoutubeDl>,
+    config_loader: Arc<Mutex<ConfigLoader>>,
+    cache_and_http: Arc<CacheAndHttp>,
+}
+
+impl Players {
+    /// The guild's player, which is started if the guild has none yet.
+    fn get(&self, guild_id: GuildId) -> Player {
+        if let Some(player) = self.existing(guild_id) {
+            return player;
+        }
+        self.start(guild_id, PlaylistState::default())
+    }
+
+    fn existing(&self, guild_id: GuildId) -> Option<Player> {
+        self.players.lock().get(&guild_id).cloned()
+    }
+
+    /// Starts the guild's player with the given queues, e.g. the ones saved before a restart.
+    fn start(&self, guild_id: GuildId, state: PlaylistState) -> Player {
+        let (sender, receiver) = mpsc::channel();
+        let player = Player::new(sender);
+        let property = VoiceManagerProperties::with_state(
+            &mut self.config_loader.lock(),
+            guild_id,
+            self.youtube_dl.clone(),
+            player.sender(),
+            state,
+        );
+        // Another command may have started the player meanwhile, then this one is dropped.
+        let player = self
+            .players
+            .lock()
+            .entry(guild_id)
+            .or_insert_with(|| {
+                let players = self.clone();
+                let own = player.clone();
+                thread::spawn(move || players.supervise(guild_id, own, receiver, property));
+                player
+            })
+            .clone();
+        player
+    }
+
+    /// Wakes the guild's player. Guilds without one have nothing to play.
+    fn notify(&self, guild_id: GuildId) {
+        if let Some(player) = self.existing(guild_id) {
+            player.notify();
+        }
+    }
+
+    fn notify_all(&self) {
+        for player in self.players.lock().values() {
+            player.notify();
+        }
+    }
+
+    fn shutdown(&self) {
+        for (_, player) in self.players.lock().drain() {
+            player.shutdown();
+        }
+    }
+
+    /// Runs the guild's player. When it panics, e.g. inside a `Query`, its handle is dropped
+    /// from the players and a new player takes over the queue, so the guild isn't left with a
+    /// player that no longer answers until the bot restarts.
+    fn supervise(
+        &self,
+        guild_id: GuildId,
+        player: Player,
+        receiver: Receiver<PlayerMessage>,
+        mut property: VoiceManagerProperties,
+    ) {
+        let run = panic::catch_unwind(AssertUnwindSafe(|| {
+            self.run(guild_id, &player, &receiver, &mut property)
+        }));
+        if run.is_ok() {
+            return;
+        }
+        drop(receiver);
+        // Shutting down already removed it, then there is nothing to take over.
+        if self.players.lock().remove(&guild_id).is_none() {
+            return;
+        }
+        error!("{}: The player failed, starting a new one", guild_id);
+        self.start(guild_id, property.snapshot());
+    }
+
+    fn run(
+        &self,
+        guild_id: GuildId,
+        player: &Player,
+        receiver: &Receiver<PlayerMessage>,
+        property: &mut VoiceManagerProperties,
+    ) {
+        loop {
+            let message = match self.step(guild_id, player, property) {
+                Some(timeout) => match receiver.recv_timeout(timeout) {
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    message => message.ok(),
+                },
+                None => receiver.recv().ok(),
+            };
+            match message {
+                Some(PlayerMessage::TrackEnd) | Some(PlayerMessage::Update) => {}
+                Some(PlayerMessage::Enqueue(Enqueue::Url {
+                    url,
+                    label,
+                    request,
+                    accept,
+                    on_resolved,
+                })) => property
+                    .playlist
+                    .push(url, label, request, accept, on_resolved),
+                Some(PlayerMessage::Enqueue(Enqueue::Tracks(tracks, request))) => {
+                    for track in tracks {
+                        property.playlist.push_track(track, request);
+                    }
+                }
+                Some(PlayerMessage::Skip(reply)) => {
+                    let track = property
+                        .current_audio
+                        .as_ref()
+                        .map(|now_playing| now_playing.track.clone());
+                    property.finish_current(true);
+                    // The stopped stream wakes the player, which then starts the next track.
+                    if let Some(handler) = self.voice_manager.lock().get_mut(guild_id) {
+                        handler.stop();
+                    }
+                    let _ = reply.send(track);
+                }
+                Some(PlayerMessage::Pause(paused, reply)) => {
+                    property.auto_paused = false;
+                    let result = property.current_audio.as_ref().map(|now_playing| {
+                        let mut audio = now_playing.audio.lock();
+                        let changed = audio.playing == paused;
+                        if changed && paused {
+                            audio.pause();
+                        } else if changed {
+                            audio.play();
+                        }
+                        (now_playing.track.clone(), changed)
+                    });
+                    let _ = reply.send(result);
+                }
+                Some(PlayerMessage::Stop(reply)) => {
+                    let mut manager = self.voice_manager.lock();
+                    let channel_id = manager.get(guild_id).map(|handler| handler.channel_id);
+                    manager.leave(guild_id);
+                    drop(manager);
+                    property.disconnected();
+                    let _ = reply.send(channel_id);
+                }
+                Some(PlayerMessage::Query(query)) => query(property),
+                Some(PlayerMessage::Shutdown) | None => return,
+            }
+        }
+    }
+
+    /// Leaves the voice channel once a timeout expired and starts the next track when nothing is
+    /// playing. Returns how long until the next timeout expires or the next track has to be
+    /// prepared, if either is due.
+    fn step(
+        &self,
+        guild_id: GuildId,
+        player: &Player,
+        property: &mut VoiceManagerProperties,
+    ) -> Option<Duration> {
+        let (deadline, pause_when_alone, fair, prefetch, crossfade, autoplaylist_path) = {
+            let mut config_loader = self.config_loader.lock();
+            (
+                property.leave_deadline(&mut config_loader, guild_id),
+                CONF_PAUSE_WHEN_ALONE.get_guild_value(&mut config_loader, guild_id),
+                CONF_FAIR_QUEUE.get_guild_value(&mut config_loader, guild_id),
+                CONF_PREFETCH.get_value(&mut config_loader),
+                CONF_CROSSFADE.get_guild_value(&mut config_loader, guild_id),
+                config_loader.autoplaylist_path(guild_id),
+            )
+        };
+        property.playlist.set_autoplaylist(&autoplaylist_path);
+
+        if let Some((deadline, reason)) = deadline {
+            if deadline <= Instant::now() {
+                self.voice_manager.lock().leave(guild_id);
+                info!("{}: {}", guild_id, reason);
+                let request = property
+                    .current_audio
+                    .as_ref()
+                    .and_then(|now_playing| now_playing.request);
+                if let Some(id) =
+                    announce_channel(&mut self.config_loader.lock(), guild_id, request)
+                {
+                    let _ = id.say(
+                        self.cache_and_http.http.clone(),
+                        format!("```{}```", reason),
+                    );
+                }
+                property.disconnected();
+                return None;
+            }
+        }
+
+        // Nothing can play until the bot is in a voice channel.
+        self.voice_manager.lock().get(guild_id)?;
+        property.playlist.start_autoplaylist();
+        property.playlist.set_fair(fair);
+        let is_playing = property
+            .current_audio
+            .as_ref()
+            .is_some_and(|now_playing| !now_playing.ended());
+        // Nobody would hear the next track, so it waits until someone joins.
+        let waiting = pause_when_alone && property.alone_since.is_some();
+        if !is_playing && !waiting {
+            property.finish_current(false);
+            self.start_next(guild_id, player, property, Duration::default());
+        }
+        let prepare = self.prepare_next(guild_id, player, property, prefetch, crossfade);
+
+        let timeout = property
+            .leave_deadline(&mut self.config_loader.lock(), guild_id)
+            .map(|(deadline, _)| deadline.saturating_duration_since(Instant::now()));
+        timeout.into_iter().chain(prepare).min()
+    }
+
+    /// Plays the next track that can be streamed, fading it in over `fade_in` alongside
+    /// whatever plays already. Returns whether a track started.
+    fn start_next(
+        &self,
+        guild_id: GuildId,
+        player: &Player,
+        property: &mut VoiceManagerProperties,
+        fade_in: Duration,
+    ) -> bool {
+        loop {
+            match self.play_next(guild_id, player, property, fade_in) {
+                Ok(now_playing) => {
+                    property.idle_since = None;
+                    self.announce_playing(guild_id, &now_playing);
+                    property.current_audio = Some(now_playing);
+                    return true;
+                }
+                // Nothing to play until a track is queued or resolved.
+                Err((error, _)) if error.is_empty() => {
+                    if property.playlist.len() == 0 {
+                        property.idle_since.get_or_insert_with(Instant::now);
+                    }
+                    return false;
+                }
+                // The track couldn't be streamed, go on with the next one.
+                Err((error, channel_id)) => {
+                    if let Some(id) = channel_id {
+                        let _ = id.say(self.cache_and_http.http.clone(), error);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Starts the stream of the track that plays next once the current one has less than
+    /// `prefetch` left, and crossfades into it once the current one has less than `crossfade`
+    /// left. Returns how long until that is due, if it is. Crossfades at most once, and only into
+    /// tracks longer than `crossfade`, so looping a short track can't keep starting it over.
+    fn prepare_next(
+        &self,
+        guild_id: GuildId,
+        player: &Player,
+        property: &mut VoiceManagerProperties,
+        prefetch: Duration,
+        crossfade: Duration,
+    ) -> Option<Duration> {
+        let prefetch = cmp::max(prefetch, crossfade);
+        let mut crossfaded = false;
+        loop {
+            let (remaining, stream) = match &property.current_audio {
+                Some(now_playing) if !now_playing.ended() && now_playing.audio.lock().playing => {
+                    (now_playing.remaining()?, now_playing.stream.clone())
+                }
+                // A paused track could end any time after it's resumed.
+                _ => return None,
+            };
+            if remaining > prefetch {
+                return Some(remaining - prefetch);
+            }
+
+            let next = match property.loop_mode {
+                LoopMode::Track => property
+                    .current_audio
+                    .as_ref()
+                    .map(|now_playing| now_playing.track.clone()),
+                _ => property.playlist.peek(),
+            }?;
+            let prefetched = property
+                .prefetched
+                .as_ref()
+                .is_some_and(|prefetched| prefetched.url == next.webpage_url);
+            if !prefetched {
+                debug!("{}: Prefetching {}", guild_id, next.webpage_url);
+                let source =
+                    match self
+                        .youtube_dl
+                        .stream(&next.webpage_url, Duration::default(), prefetch)
+                    {
+                        Ok(source) => Some(source),
+                        Err(err) => {
+                            warn!(
+                                "{}: Couldn't prefetch {}: {}",
+                                guild_id, next.webpage_url, err
+                            );
+                            None
+                        }
+                    };
+                property.prefetched = Some(Prefetched {
+                    url: next.webpage_url.clone(),
+                    source,
+                });
+            }
+
+            // Tracks that would end during their own fade in start once this one ended.
+            let fades = !next.is_live && next.duration.is_some_and(|length| length > crossfade);
+            if crossfade == Duration::default() || crossfaded || !fades {
+                return None;
+            }
+            if remaining > crossfade {
+                return Some(remaining - crossfade);
+            }
+            // Without a stream to fade into, the next track starts once this one ends.
+            if property
+                .prefetched
+                .as_ref()
+                .is_none_or(|prefetched| prefetched.source.is_none())
+            {
+                return None;
+            }
+            property.finish_current(false);
+            if self.start_next(guild_id, player, property, remaining) {
+                stream.fade_out(remaining);
+            }
+            crossfaded = true;
+        }
+    }
+
+    /// Starts the next track of the guild's playlist, using its prefetched stream if there is
+    /// one. The stream is started before the voice manager is locked, so other guilds don't wait
+    /// for youtube-dl. A track faded in over `fade_in` plays alongside the current one.
+    fn play_next(
+        &self,
+        guild_id: GuildId,
+        player: &Player,
+        property: &mut VoiceManagerProperties,
+        fade_in: Duration,
+    ) -> Result<NowPlaying, (String, Option<ChannelId>)> {
+        let (track, request, from_autoplaylist) = match property.playlist.poll() {
+            Some(next) => next,
+            None => return Err(("".to_string(), None)),
+        };
+        let prefetched = property
+            .prefetched
+            .take()
+            .filter(|prefetched| prefetched.url == track.webpage_url)
+            .and_then(|prefetched| prefetched.source);
+        let source = match prefetched {
+            Some(source) => source,
+            None => match self.youtube_dl.stream(
+                &track.webpage_url,
+                Duration::default(),
+                Duration::default(),
+            ) {
+                Ok(source) => source,
+                Err(err) => {
+                    return Err((
+                        format!("```Couldn't play \"{}\": {}```", track.title, err),
+                        request.map(|request| request.channel_id),
+                    ))
+                }
+            },
+        };
+
+        let mut manager = self.voice_manager.lock();
+        let handler = match manager.get_mut(guild_id) {
+            Some(handler) => handler,
+            // Left meanwhile, the track plays once the bot is back.
+            None => {
+                if !from_autoplaylist {
+                    property.playlist.requeue(track, request, true);
+                }
+                return Err(("".to_string(), None));
+            }
+        };
+        info!(
+            "{}: Start playing {} {} ({})",
+            guild_id, track.title, track.webpage_url, track.extractor
+        );
+        handler.deafen(true);
+        let (source, stream) = NotifyingSource::wrap(source, player.sender(), fade_in);
+        let audio = if fade_in > Duration::default() {
+            handler.play_returning(source)
+        } else {
+            handler.play_only(source)
+        };
+        audio.lock().volume(property.volume as f32 / 100.0);
+        Ok(NowPlaying {
+            track,
+            request,
+            from_autoplaylist,
+            audio,
+            offset: Duration::default(),
+            stream,
+            skip_votes: HashSet::new(),
+        })
+    }
+
+    fn announce_playing(&self, guild_id: GuildId, now_playing: &NowPlaying) {
+        let announce_channel_id = announce_channel(
+            &mut self.config_loader.lock(),
+            guild_id,
+            now_playing.request,
+        );
+        if let Some(id) = announce_channel_id {
+            let requester = match now_playing.request {
+                Some(request) => format!(
+                    "requested by {}",
+                    display_name(
+                        &CacheRwLock::from(self.cache_and_http.cache.clone()),
+                        guild_id,
+                        request.user_id,
+                    )
+                ),
+                None => "from the autoplaylist".to_string(),
+            };
+            let _ = id.say(
+                self.cache_and_http.http.clone(),
+                format!(
+                    "```Playing {}, {}```",
+                    now_playing.track.describe(),
+                    requester
+                ),
+            );
+        }
+    }
+
+    /// The state of every guild, asked from each player in turn.
+    fn collect_state(&self) -> HashMap<GuildId, GuildState> {
+        let players: Vec<(GuildId, Player)> = self
+            .players
+            .lock()
+            .iter()
+            .map(|(guild_id, player)| (*guild_id, player.clone()))
+            .collect();
+        let playlists: Vec<(GuildId, PlaylistState)> = players
+            .into_iter()
+            .filter_map(|(guild_id, player)| {
+                player
+                    .query(|property| property.snapshot())
+                    .map(|playlist| (guild_id, playlist))
+            })
+            .collect();
+
+        let manager = self.voice_manager.lock();
+        playlists
+            .into_iter()
+            .map(|(guild_id, playlist)| {
+                let voice_channel_id = manager.get(guild_id).and_then(|handler| handler.channel_id);
+                (
+                    guild_id,
+                    GuildState {
+                        voice_channel_id,
+                        playlist,
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+/// Saves the state of every guild each `state_save_interval` until `stop` receives something.
+fn save_state_periodically(players: &Players, stop: Receiver<()>) {
+    loop {
+        // Read on every pass so a config reload applies to the next save. A zero interval turns
+        // saving off until a reload turns it on again.
+        let (state_path, save_interval) = {
+            let mut config_loader = players.config_loader.lock();
+            (
+                CONF_STATE_PATH.get_value(&mut config_loader),
+                CONF_STATE_SAVE_INTERVAL.get_value(&mut config_loader),
+            )
+        };
+        let wait = if save_interval > Duration::default() {
+            save_interval
+        } else {
+            Duration::from_secs(60)
+        };
+        match stop.recv_timeout(wait) {
+            Err(RecvTimeoutError::Timeout) => {}
+            _ => return,
+        }
+        if save_interval > Duration::default() {
+            save_state(&state_path, &players.collect_state());
+        }
+    }
 }
 
 impl TypeMapKey for VoiceManager {
@@ -55,89 +778,280 @@ impl TypeMapKey for VoiceManager {
 
 group!({
     name: "voice_client",
-    options: {},
-    commands: [summon, leave, play, queue, skip]
+    options: {
+        checks: [User],
+    },
+    commands: [
+        summon, leave, play, search, pick, queue, skip, volume, pause, resume, np, seek, remove,
+        move_entry, clear, shuffle, skipto, loop_mode
+    ]
 });
 
 pub fn register_module(client: &mut Client, standard_framework: &mut StandardFramework) {
-    let audio_monitor_active = Arc::new(AtomicBool::new(true));
-    let audio_monitor_active_clone = audio_monitor_active.clone();
-    let properties_map = Arc::new(Mutex::new(HashMap::new()));
-    let properties_map_clone = properties_map.clone();
-    let cache_and_http = client.cache_and_http.clone();
-
-    let voice_manager = Arc::new(Mutex::new(VoiceManager {
+    let config_loader = client
+        .data
+        .read()
+        .get::<ConfigLoader>()
+        .expect("Expected ConfigLoader in ShareMap.")
+        .clone();
+    let youtube_dl = Arc::new(youtube_dl_from_config(&mut config_loader.lock()));
+    let players = Players {
+        players: Arc::new(Mutex::new(HashMap::new())),
         voice_manager: client.voice_manager.clone(),
-        audio_monitor_active,
-        audio_monitor: None,
-        properties: properties_map,
-    }));
-    let voice_manager_clone = voice_manager.clone();
-
-    voice_manager.lock().audio_monitor = Some(thread::spawn(move || {
-        let active = audio_monitor_active_clone.clone();
-        while active.load(Ordering::SeqCst) {
-            thread::sleep(Duration::from_secs(1));
-            let mut properties = properties_map_clone.lock();
-            for (guild_id, property) in &mut *properties {
-                let is_playing = match property.current_audio.as_ref() {
-                    Some((_, audio)) => !audio.lock().finished,
-                    None => false,
-                };
+        youtube_dl: youtube_dl.clone(),
+        config_loader: config_loader.clone(),
+        cache_and_http: client.cache_and_http.clone(),
+    };
 
-                if !is_playing && active.load(Ordering::SeqCst) {
-                    let client_voice_manager = {
-                        let voice_manager = voice_manager_clone.lock();
-                        voice_manager.voice_manager.clone()
-                    };
-                    let mut client_voice_manager_locked = client_voice_manager.lock();
-                    if let Some(handler) = client_voice_manager_locked.get_mut(guild_id) {
-                        match play_music(handler, &mut property.playlist) {
-                            Ok((locked_audio, title, channel_id)) => {
-                                property.current_audio = Some((title.clone(), locked_audio));
-                                if let Some(id) = channel_id {
-                                    let _ = id.say(
-                                        cache_and_http.http.clone(),
-                                        format!("```Playing \"{}\"```", title),
-                                    );
-                                }
-                            }
-                            Err((error, channel_id)) => {
-                                if let Some(id) = channel_id {
-                                    let _ = id.say(cache_and_http.http.clone(), error);
-                                }
-                            }
-                        }
-                    }
-                }
-            }
+    let (states, rejoin) = {
+        let mut config_loader = config_loader.lock();
+        (
+            state::load(&CONF_STATE_PATH.get_value(&mut config_loader)),
+            CONF_REJOIN_VOICE_CHANNELS.get_value(&mut config_loader),
+        )
+    };
+    let mut rejoin_channels = HashMap::new();
+    for (guild_id, guild_state) in states {
+        if let (true, Some(channel_id)) = (rejoin, guild_state.voice_channel_id) {
+            rejoin_channels.insert(guild_id, channel_id);
         }
+        players.start(guild_id, guild_state.playlist);
+    }
+
+    let (stop_saving, stop) = mpsc::channel();
+    let players_clone = players.clone();
+    let state_saver = thread::spawn(move || save_state_periodically(&players_clone, stop));
+
+    let voice_manager = Arc::new(Mutex::new(VoiceManager {
+        voice_manager: client.voice_manager.clone(),
+        youtube_dl,
+        rejoin_channels,
+        players,
+        state_saver: Some((stop_saving, state_saver)),
     }));
 
     let mut data = client.data.write();
 
     if data.get::<VoiceManager>().is_none() {
-        data.insert::<VoiceManager>(voice_manager.clone());
+        data.insert::<VoiceManager>(voice_manager);
     } else {
         panic!("VoiceManager already inserted");
     }
     standard_framework.group_add(&VOICE_CLIENT_GROUP);
 }
 
+/// Where messages about the guild's playback go: the guild's announcement channel, or else the
+/// channel the track was requested in.
+fn announce_channel(
+    config_loader: &mut ConfigLoader,
+    guild_id: GuildId,
+    request: Option<Request>,
+) -> Option<ChannelId> {
+    match CONF_ANNOUNCE_CHANNEL.get_guild_value(config_loader, guild_id) {
+        0 => request.map(|request| request.channel_id),
+        id => Some(ChannelId(id)),
+    }
+}
+
 pub fn unregister_module(data: Arc<RwLock<ShareMap>>) {
-    let thread = {
-        let mut share_map = data.write();
+    let (players, state_saver) = {
+        let share_map = data.read();
         let mut voice_manager = share_map
-            .get_mut::<VoiceManager>()
+            .get::<VoiceManager>()
             .expect("Expected VoiceManager in ShareMap.")
             .lock();
+        (
+            voice_manager.players.clone(),
+            voice_manager.state_saver.take(),
+        )
+    };
+    if let Some((stop, thread)) = state_saver {
+        let _ = stop.send(());
+        thread.join().unwrap();
+    }
+    let states = players.collect_state();
+    players.shutdown();
+
+    let share_map = data.read();
+    let state_path = CONF_STATE_PATH.get_value(
+        &mut share_map
+            .get::<ConfigLoader>()
+            .expect("Expected ConfigLoader in ShareMap.")
+            .lock(),
+    );
+    save_state(&state_path, &states);
+}
+
+/// Applies a reloaded configuration. The extractor settings are replaced here, and every player
+/// re-reads its timeouts and autoplaylist when notified. Everything else is read when it is used,
+/// except `default_volume`: players that already run keep their volume.
+pub fn reload_module(data: &Arc<RwLock<ShareMap>>) {
+    let share_map = data.read();
+    let youtube_dl = youtube_dl_from_config(
+        &mut share_map
+            .get::<ConfigLoader>()
+            .expect("Expected ConfigLoader in ShareMap.")
+            .lock(),
+    );
+    if let Some(voice_manager) = share_map.get::<VoiceManager>() {
+        let voice_manager = voice_manager.lock();
+        voice_manager.youtube_dl.reconfigure(youtube_dl);
+        // Timeouts and autoplaylists may have changed.
+        voice_manager.players.notify_all();
+    }
+}
+
+/// Lets the guild's player pick up changed settings, e.g. its timeouts or autoplaylist.
+pub fn guild_config_changed(data: &Arc<RwLock<ShareMap>>, guild_id: GuildId) {
+    if let Some(voice_manager) = data.read().get::<VoiceManager>() {
+        voice_manager.lock().players.notify(guild_id);
+    }
+}
+
+fn youtube_dl_from_config(config_loader: &mut ConfigLoader) -> YoutubeDl {
+    YoutubeDl::new(
+        &CONF_EXTRACTOR_PATH.get_value(config_loader),
+        CONF_EXTRACTOR_ARGS.get_value(config_loader),
+        CONF_EXTRACTOR_TIMEOUT.get_value(config_loader),
+        &CONF_SEARCH_PROVIDER.get_value(config_loader),
+    )
+}
+
+/// Rejoins the voice channels the bot was connected to before the last shutdown. Only does
+/// something the first time it is called, as the saved channels are consumed.
+pub fn rejoin_voice_channels(ctx: &Context) {
+    let voice_manager = {
+        let share_map = ctx.data.read();
+        share_map
+            .get::<VoiceManager>()
+            .expect("Expected VoiceManager in ShareMap.")
+            .clone()
+    };
+
+    let mut voice_manager_locked = voice_manager.lock();
+    let rejoin_channels = std::mem::take(&mut voice_manager_locked.rejoin_channels);
+    let mut manager = voice_manager_locked.voice_manager.lock();
+    for (guild_id, channel_id) in rejoin_channels {
+        if manager.join(guild_id, channel_id).is_none() {
+            warn!("{}: Couldn't rejoin {}", guild_id, channel_id);
+        } else {
+            voice_manager_locked.players.notify(guild_id);
+        }
+    }
+}
+
+/// Keeps track of whether the bot is alone in its voice channel, so the guild's player can leave
+/// once that lasts too long. Unless the guild turned it off, the track is paused while the bot
+/// is alone and resumed when someone joins again. Called for every voice state change.
+pub fn voice_state_update(ctx: &Context, guild_id: GuildId) {
+    let (voice_manager, pause_when_alone) = {
+        let share_map = ctx.data.read();
+        let voice_manager = match share_map.get::<VoiceManager>() {
+            Some(voice_manager) => voice_manager.clone(),
+            None => return,
+        };
+        let config_loader = share_map
+            .get::<ConfigLoader>()
+            .expect("Expected ConfigLoader in ShareMap.");
+        let pause_when_alone =
+            CONF_PAUSE_WHEN_ALONE.get_guild_value(&mut config_loader.lock(), guild_id);
+        (voice_manager, pause_when_alone)
+    };
 
-        voice_manager
-            .audio_monitor_active
-            .store(false, Ordering::SeqCst);
-        voice_manager.audio_monitor.take().unwrap()
+    let (client_voice_manager, player) = {
+        let voice_manager_locked = voice_manager.lock();
+        match voice_manager_locked.players.existing(guild_id) {
+            Some(player) => (voice_manager_locked.voice_manager.clone(), player),
+            None => return,
+        }
+    };
+    let channel_id = client_voice_manager
+        .lock()
+        .get(guild_id)
+        .and_then(|handler| handler.channel_id);
+    let alone =
+        channel_id.is_some_and(|channel_id| listeners(ctx, guild_id, channel_id).is_empty());
+
+    // The player brings the playback up to date afterwards, e.g. starts the next track.
+    let message = player.query(move |property| {
+        property.alone_since = match property.alone_since {
+            _ if !alone => None,
+            Some(since) => Some(since),
+            None => Some(Instant::now()),
+        };
+        if channel_id.is_none() {
+            property.idle_since = None;
+            return None;
+        }
+        let now_playing = property.current_audio.as_ref()?;
+        let track = &now_playing.track;
+        let mut audio = now_playing.audio.lock();
+        let message = if alone && pause_when_alone && audio.playing {
+            audio.pause();
+            property.auto_paused = true;
+            format!("Paused {} until someone joins", track.describe())
+        } else if !alone && property.auto_paused {
+            audio.play();
+            property.auto_paused = false;
+            format!("Resumed {}", track.describe())
+        } else {
+            return None;
+        };
+        Some((message, now_playing.request))
+    });
+    if let Some((message, Some(request))) = message.flatten() {
+        let _ = request
+            .channel_id
+            .say(&ctx.http, format!("```{}```", message));
+    }
+}
+
+/// Users other than bots in the voice channel.
+fn listeners(ctx: &Context, guild_id: GuildId, channel_id: ChannelId) -> Vec<UserId> {
+    let guild = match guild_id.to_guild_cached(&ctx.cache) {
+        Some(guild) => guild,
+        None => return Vec::new(),
     };
-    thread.join().unwrap();
+    let cache = ctx.cache.read();
+    let guild = guild.read();
+    guild
+        .voice_states
+        .values()
+        .filter(|voice_state| voice_state.channel_id == Some(channel_id))
+        .filter(|voice_state| match cache.user(voice_state.user_id) {
+            Some(user) => !user.read().bot,
+            None => voice_state.user_id != cache.user.id,
+        })
+        .map(|voice_state| voice_state.user_id)
+        .collect()
+}
+
+/// The bot's voice channel, if anyone but `user_id` listens there. Moving the bot away would
+/// cut them off.
+fn occupied_channel(
+    ctx: &Context,
+    client_voice_manager: &Mutex<ClientVoiceManager>,
+    guild_id: GuildId,
+    user_id: UserId,
+) -> Option<ChannelId> {
+    let channel_id = client_voice_manager
+        .lock()
+        .get(guild_id)
+        .and_then(|handler| handler.channel_id)?;
+    if listeners(ctx, guild_id, channel_id)
+        .iter()
+        .any(|listener| *listener != user_id)
+    {
+        Some(channel_id)
+    } else {
+        None
+    }
+}
+
+fn save_state(path: &str, states: &HashMap<GuildId, GuildState>) {
+    if let Err(err) = state::save(path, states) {
+        error!("Couldn't save state to {}: {}", path, err);
+    }
 }
 
 #[command]
@@ -192,28 +1106,23 @@ pub fn summon(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
             .expect("Expected Scheduler.")
             .clone()
     };
+    let client_voice_manager = voice_manager.lock().voice_manager.clone();
 
-    let config_loader = {
-        let mut share_map = ctx.data.write();
-        share_map
-            .get_mut::<ConfigLoader>()
-            .expect("Expected Dispatcher.")
-            .clone()
-    };
+    let moves_listeners = occupied_channel(ctx, &client_voice_manager, guild_id, msg.author.id)
+        .is_some_and(|current_channel_id| current_channel_id != channel_id);
+    if moves_listeners && !require_dj(ctx, msg, guild_id, "move the bot away from its listeners")? {
+        return Ok(());
+    }
 
     let voice_manager_locked = voice_manager.lock();
-    let client_voice_manager = voice_manager_locked.voice_manager.clone();
 
     match client_voice_manager.lock().join(guild_id, channel_id) {
         Some(_) => {
             let _ = msg.channel_id.say(
                 &ctx.http,
-                &format!("```Joined {}```", channel_id.name(&ctx.cache).unwrap()),
+                format!("```Joined {}```", channel_id.name(&ctx.cache).unwrap()),
             );
-            let mut properties = voice_manager_locked.properties.lock();
-            properties
-                .entry(guild_id)
-                .or_insert_with(|| VoiceManagerProperties::new(&mut config_loader.lock()));
+            voice_manager_locked.players.get(guild_id).notify();
         }
         None => {
             let _ = msg
@@ -227,30 +1136,31 @@ pub fn summon(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
 }
 
 #[command]
+#[checks(DJ)]
 pub fn leave(ctx: &mut Context, msg: &Message) -> CommandResult {
     let guild_id = match ctx.cache.read().guild_channel(msg.channel_id) {
         Some(channel) => channel.read().guild_id,
         None => return Ok(()),
     };
 
-    let share_map = ctx.data.read();
-    let manager_lock = share_map
-        .get::<VoiceManager>()
-        .expect("Expected VoiceManager in ShareMap.")
-        .lock();
-    let mut manager = manager_lock.voice_manager.lock();
+    let player = {
+        let share_map = ctx.data.read();
+        let voice_manager = share_map
+            .get::<VoiceManager>()
+            .expect("Expected VoiceManager in ShareMap.")
+            .lock();
+        voice_manager.players.existing(guild_id)
+    };
 
-    match manager.get(guild_id) {
-        Some(handler) => {
-            if let Some(channel_id) = handler.channel_id {
-                let _ = msg.channel_id.say(
-                    &ctx.http,
-                    format!("```Left {}```", channel_id.name(&ctx.cache).unwrap()),
-                );
-            } else {
-                let _ = msg.channel_id.say(&ctx.http, "```Left voice channel```");
-            }
-            manager.leave(guild_id);
+    match player.and_then(|player| player.stop()) {
+        Some(Some(channel_id)) => {
+            let _ = msg.channel_id.say(
+                &ctx.http,
+                format!("```Left {}```", channel_id.name(&ctx.cache).unwrap()),
+            );
+        }
+        Some(None) => {
+            let _ = msg.channel_id.say(&ctx.http, "```Left voice channel```");
         }
         None => {
             let _ = msg.channel_id.say(&ctx, "```Not in a voice channel```");
@@ -261,43 +1171,51 @@ pub fn leave(ctx: &mut Context, msg: &Message) -> CommandResult {
 }
 
 #[command]
-pub fn play(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
-    let url = match args.single::<String>() {
-        Ok(url) => url,
-        Err(_) => {
-            msg.channel_id
-                .say(&ctx.http, "```Must provide a URL to a video or audio```")?;
+pub fn play(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    let request = match args.remains() {
+        Some(request) => request.trim().to_string(),
+        None => {
+            msg.channel_id.say(
+                &ctx.http,
+                "```Must provide a URL to a video or audio or a search query```",
+            )?;
             return Ok(());
         }
     };
 
-    if !url.starts_with("http") {
-        msg.channel_id
-            .say(&ctx.http, "```Must provide a valid URL```")?;
-        return Ok(());
-    }
-
-    let guild = match msg.guild(&ctx.cache) {
-        Some(guild) => guild,
-        None => return Ok(()),
+    let url = if request.starts_with("http") {
+        request.clone()
+    } else {
+        let voice_manager = {
+            let share_map = ctx.data.read();
+            share_map
+                .get::<VoiceManager>()
+                .expect("Expected VoiceManager in ShareMap.")
+                .clone()
+        };
+        let youtube_dl = voice_manager.lock().youtube_dl.clone();
+        youtube_dl.search_url(&request, 1)
     };
 
-    let guild_id = guild.read().id;
+    enqueue(ctx, msg, &url, &request)
+}
 
-    let user_channel_id = match guild
-        .read()
-        .voice_states
-        .get(&msg.author.id)
-        .and_then(|voice_state| voice_state.channel_id)
-    {
-        Some(channel_id) => channel_id,
+#[command]
+pub fn search(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    let query = match args.remains() {
+        Some(query) => query.trim().to_string(),
         None => {
             msg.channel_id
-                .say(&ctx.http, "```You must be in a voice channel```")?;
+                .say(&ctx.http, "```Must provide a search query```")?;
             return Ok(());
         }
     };
 
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+
     let voice_manager = {
         let share_map = ctx.data.read();
         share_map
@@ -314,47 +1232,368 @@ pub fn play(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
             .clone()
     };
 
-    let voice_manager_locked = voice_manager.lock();
-    let mut manager = voice_manager_locked.voice_manager.lock();
-    let mut properties = voice_manager_locked.properties.lock();
+    let (count, timeout, prefix) = {
+        let mut config_loader = config_loader.lock();
+        (
+            CONF_SEARCH_RESULTS.get_value(&mut config_loader) as usize,
+            CONF_SEARCH_TIMEOUT.get_value(&mut config_loader).as_secs(),
+            CONF_PREFIX.get_guild_value(&mut config_loader, guild_id),
+        )
+    };
 
-    match manager.get_mut(guild_id) {
-        Some(handler) => {
-            if handler.channel_id.unwrap() != user_channel_id {
-                handler.switch_to(user_channel_id);
-            }
-        }
-        None => {
-            if manager.join(guild_id, user_channel_id).is_none() {
-                let _ = msg.channel_id.say(&ctx.http, "```Can't join channel```");
-            }
+    let (youtube_dl, players) = {
+        let voice_manager_locked = voice_manager.lock();
+        (
+            voice_manager_locked.youtube_dl.clone(),
+            voice_manager_locked.players.clone(),
+        )
+    };
+
+    let results = match youtube_dl.search(&query, count) {
+        Ok(results) if !results.is_empty() => results,
+        Ok(_) => {
+            msg.channel_id
+                .say(&ctx.http, format!("```No results for {}```", query))?;
+            return Ok(());
+        }
+        Err(err) => {
+            msg.channel_id.say(
+                &ctx.http,
+                format!("```Couldn't search {}: {}```", query, err),
+            )?;
+            return Ok(());
+        }
+    };
+
+    let mut output = "```".to_string();
+    for (index, track) in results.iter().enumerate() {
+        output.push_str(&format!("{}. {}\n", index + 1, track.describe()));
+    }
+    output.push_str(&format!(
+        "\nPick one with {}pick <number> within {}s```",
+        prefix, timeout
+    ));
+
+    let author_id = msg.author.id;
+    players.get(guild_id).query(move |property| {
+        property
+            .search_results
+            .insert(author_id, (Instant::now(), results))
+    });
+    let _ = msg.channel_id.say(&ctx.http, output);
+
+    Ok(())
+}
+
+#[command]
+pub fn pick(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+
+    let number = match args.single::<usize>() {
+        Ok(number) => number,
+        Err(_) => {
+            msg.channel_id.say(
+                &ctx.http,
+                "```Must provide the number of a search result```",
+            )?;
+            return Ok(());
         }
     };
 
-    let property = properties
-        .entry(guild_id)
-        .or_insert_with(|| VoiceManagerProperties::new(&mut config_loader.lock()));
-    match property
-        .playlist
-        .push(url.to_string(), Some(msg.channel_id))
+    let voice_manager = {
+        let share_map = ctx.data.read();
+        share_map
+            .get::<VoiceManager>()
+            .expect("Expected VoiceManager in ShareMap.")
+            .clone()
+    };
+
+    let config_loader = {
+        let share_map = ctx.data.read();
+        share_map
+            .get::<ConfigLoader>()
+            .expect("Expected ConfigLoader in ShareMap.")
+            .clone()
+    };
+
+    let timeout = CONF_SEARCH_TIMEOUT.get_value(&mut config_loader.lock());
+
+    let author_id = msg.author.id;
+    let player = voice_manager.lock().players.existing(guild_id);
+    let results = player.as_ref().and_then(|player| {
+        player
+            .query(move |property| property.search_results.remove(&author_id))
+            .flatten()
+    });
+
+    let track = match results {
+        Some((searched_at, _)) if searched_at.elapsed() > timeout => {
+            msg.channel_id
+                .say(&ctx.http, "```Your search has expired, search again```")?;
+            return Ok(());
+        }
+        Some((searched_at, mut results)) => {
+            if number == 0 || number > results.len() {
+                msg.channel_id.say(
+                    &ctx.http,
+                    format!("```Pick a number between 1 and {}```", results.len()),
+                )?;
+                if let Some(player) = player {
+                    player.query(move |property| {
+                        property
+                            .search_results
+                            .insert(author_id, (searched_at, results))
+                    });
+                }
+                return Ok(());
+            }
+            results.remove(number - 1)
+        }
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "```Nothing to pick from, search first```")?;
+            return Ok(());
+        }
+    };
+
+    enqueue(ctx, msg, &track.webpage_url, &track.title)
+}
+
+/// Joins the author's voice channel if needed and adds `url` to the guild's queue, reporting
+/// the result. `request` is what the user asked for and is used in error replies.
+fn enqueue(ctx: &mut Context, msg: &Message, url: &str, request: &str) -> CommandResult {
+    let guild = match msg.guild(&ctx.cache) {
+        Some(guild) => guild,
+        None => return Ok(()),
+    };
+
+    let guild_id = guild.read().id;
+
+    let user_channel_id = match guild
+        .read()
+        .voice_states
+        .get(&msg.author.id)
+        .and_then(|voice_state| voice_state.channel_id)
     {
-        Ok(title) => {
-            let _ = msg
-                .channel_id
-                .say(&ctx.http, format!("```Added \"{}\" to queue```", title));
+        Some(channel_id) => channel_id,
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "```You must be in a voice channel```")?;
+            return Ok(());
         }
-        Err(_) => {
-            let _ = msg
-                .channel_id
-                .say(&ctx.http, format!("```Couldn't play {}```", url));
+    };
+
+    let voice_manager = {
+        let share_map = ctx.data.read();
+        share_map
+            .get::<VoiceManager>()
+            .expect("Expected VoiceManager in ShareMap.")
+            .clone()
+    };
+
+    let config_loader = {
+        let share_map = ctx.data.read();
+        share_map
+            .get::<ConfigLoader>()
+            .expect("Expected ConfigLoader in ShareMap.")
+            .clone()
+    };
+
+    // Others listening in another channel would lose the music, only DJs may move the bot there
+    // with `summon`.
+    let client_voice_manager = voice_manager.lock().voice_manager.clone();
+    if let Some(current_channel_id) =
+        occupied_channel(ctx, &client_voice_manager, guild_id, msg.author.id)
+    {
+        if current_channel_id != user_channel_id {
+            msg.channel_id.say(
+                &ctx.http,
+                format!(
+                    "```I'm playing in {}, join it to queue tracks```",
+                    current_channel_id.name(&ctx.cache).unwrap_or_default()
+                ),
+            )?;
+            return Ok(());
         }
     }
 
+    // Only hold the global locks for joining; resolving happens in the background so other
+    // guilds and the players are never blocked on youtube-dl.
+    let (player, youtube_dl) = {
+        let voice_manager_locked = voice_manager.lock();
+        let mut manager = voice_manager_locked.voice_manager.lock();
+
+        match manager.get_mut(guild_id) {
+            Some(handler) => {
+                if handler.channel_id.unwrap() != user_channel_id {
+                    handler.switch_to(user_channel_id);
+                }
+            }
+            None => {
+                if manager.join(guild_id, user_channel_id).is_none() {
+                    let _ = msg.channel_id.say(&ctx.http, "```Can't join channel```");
+                }
+            }
+        };
+
+        (
+            voice_manager_locked.players.get(guild_id),
+            voice_manager_locked.youtube_dl.clone(),
+        )
+    };
+
+    let (max_items, max_queue_length, mut max_user_queue_length, mut max_duration) = {
+        let mut config_loader = config_loader.lock();
+        (
+            CONF_PLAYLIST_MAX_ITEMS.get_value(&mut config_loader) as usize,
+            CONF_MAX_QUEUE_LENGTH.get_guild_value(&mut config_loader, guild_id) as usize,
+            CONF_MAX_USER_QUEUE_LENGTH.get_guild_value(&mut config_loader, guild_id) as usize,
+            CONF_MAX_TRACK_DURATION.get_guild_value(&mut config_loader, guild_id),
+        )
+    };
+    // The per-user limits keep anyone from taking over the channel, DJs are trusted with that.
+    if permissions::role(ctx, msg, guild_id) >= Role::Dj {
+        max_user_queue_length = 0;
+        max_duration = Duration::default();
+    }
+    let author_id = msg.author.id;
+    let (queued, queued_by_author) = player
+        .query(move |property| {
+            (
+                property.playlist.len(),
+                property.playlist.requested_by(author_id),
+            )
+        })
+        .unwrap_or_default();
+    let capacity = match max_queue_length {
+        0 => usize::MAX,
+        max => max.saturating_sub(queued),
+    };
+    if capacity == 0 {
+        msg.channel_id.say(
+            &ctx.http,
+            format!("```Queue is full ({} tracks)```", max_queue_length),
+        )?;
+        return Ok(());
+    }
+    let capacity = match max_user_queue_length {
+        0 => capacity,
+        max => capacity.min(max.saturating_sub(queued_by_author)),
+    };
+    if capacity == 0 {
+        msg.channel_id.say(
+            &ctx.http,
+            format!(
+                "```You already have {} tracks in the queue```",
+                max_user_queue_length
+            ),
+        )?;
+        return Ok(());
+    }
+
+    let http = ctx.http.clone();
+    let channel_id = msg.channel_id;
+    let requested_by = Request {
+        user_id: msg.author.id,
+        channel_id,
+        requested_at: SystemTime::now(),
+        message_id: Some(msg.id),
+    };
+    let request = request.to_string();
+
+    if ytdl::is_playlist_url(url) {
+        let max_items = max_items.min(capacity);
+        let url = url.to_string();
+        thread::spawn(move || match youtube_dl.expand_playlist(&url, max_items) {
+            Ok((title, tracks)) => {
+                let total = tracks.len();
+                let tracks: Vec<TrackInfo> = tracks
+                    .into_iter()
+                    .filter(|track| check_duration(track, max_duration).is_ok())
+                    .collect();
+                let count = tracks.len();
+                player.enqueue(Enqueue::Tracks(tracks, requested_by));
+                let output = if count < total {
+                    format!(
+                        "Added {} tracks from {}, {} were longer than {}",
+                        count,
+                        title,
+                        total - count,
+                        format_duration(max_duration)
+                    )
+                } else {
+                    format!("Added {} tracks from {}", count, title)
+                };
+                let _ = channel_id.say(&http, format!("```{}```", output));
+            }
+            Err(err) => {
+                let _ = channel_id.say(&http, format!("```Couldn't play {}: {}```", request, err));
+            }
+        });
+        return Ok(());
+    }
+
+    player.enqueue(Enqueue::Url {
+        url: url.to_string(),
+        label: request.clone(),
+        request: requested_by,
+        accept: Box::new(move |track| check_duration(track, max_duration)),
+        on_resolved: Box::new(move |result| match result {
+            Ok(track) => {
+                let _ = channel_id.say(&http, format!("```Added {} to queue```", track.describe()));
+            }
+            Err(err) => {
+                let _ = channel_id.say(&http, format!("```Couldn't play {}: {}```", request, err));
+            }
+        }),
+    });
+
     Ok(())
 }
 
+/// Rejects tracks longer than `max_duration`, unless it is zero. Live streams have no end, so
+/// they are rejected as well.
+fn check_duration(track: &TrackInfo, max_duration: Duration) -> Result<(), String> {
+    if max_duration == Duration::default() {
+        return Ok(());
+    }
+    if track.is_live {
+        return Err(format!(
+            "live streams aren't allowed while tracks are limited to {}",
+            format_duration(max_duration)
+        ));
+    }
+    match track.duration {
+        Some(duration) if duration > max_duration => Err(format!(
+            "{} is longer than the limit of {}",
+            track.duration_text(),
+            format_duration(max_duration)
+        )),
+        _ => Ok(()),
+    }
+}
+
 #[command]
-pub fn queue(ctx: &mut Context, msg: &Message) -> CommandResult {
+pub fn queue(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+    let page = if args.is_empty() {
+        1
+    } else {
+        match args.single::<usize>() {
+            Ok(page) if page > 0 => page,
+            _ => {
+                msg.channel_id.say(&ctx.http, "```Usage: queue [page]```")?;
+                return Ok(());
+            }
+        }
+    };
+
     let voice_manager = {
         let mut share_map = ctx.data.write();
         share_map
@@ -371,79 +1610,799 @@ pub fn queue(ctx: &mut Context, msg: &Message) -> CommandResult {
             .clone()
     };
 
-    let voice_manager_properties = voice_manager.lock().properties.clone();
-    let mut voice_manager_properties_locked = voice_manager_properties.lock();
-    let property = voice_manager_properties_locked
-        .entry(msg.guild_id.unwrap())
-        .or_insert_with(|| VoiceManagerProperties::new(&mut config_loader.lock()));
+    let fair = CONF_FAIR_QUEUE.get_guild_value(&mut config_loader.lock(), guild_id);
+    let player = voice_manager.lock().players.existing(guild_id);
+    let (queue, current, loop_mode) = match player {
+        Some(player) => match player.query(move |property| {
+            property.playlist.set_fair(fair);
+            let current = property
+                .current_audio
+                .as_ref()
+                .map(|now_playing| (now_playing.track.clone(), now_playing.elapsed()));
+            (property.playlist.get_queue(), current, property.loop_mode)
+        }) {
+            Some(snapshot) => snapshot,
+            None => return Ok(()),
+        },
+        // Nothing was queued in a guild without a player.
+        None => (Vec::new(), None, LoopMode::Off),
+    };
 
-    let queue = property.playlist.get_queue();
-    let mut output = "```".to_string();
-    if let Some((title, _)) = &property.current_audio {
-        output.push_str(&format!("Playing right now \"{}\"\n\n", title));
+    // Time until each entry starts. Once an entry of unknown length is passed, the times are
+    // only lower bounds and get a `+`.
+    let mut eta = Duration::default();
+    let mut eta_known = true;
+    let mut header = String::new();
+    if let Some((track, position)) = current {
+        header.push_str(&format!(
+            "Playing right now \"{}\" [{}/{}]\n\n",
+            truncate(&track.title, MAX_TITLE_LENGTH),
+            format_duration(position),
+            track.duration_text()
+        ));
+        match track.duration {
+            Some(duration) if !track.is_live => {
+                eta += duration.checked_sub(position).unwrap_or_default()
+            }
+            _ => eta_known = false,
+        }
+    }
+
+    let mut lines = Vec::new();
+    let mut regular_index = 0;
+    let mut autoplaylist_index = 0;
+    for entry in &queue {
+        let number = if entry.from_autoplaylist {
+            autoplaylist_index += 1;
+            format!("A{}", autoplaylist_index)
+        } else {
+            regular_index += 1;
+            regular_index.to_string()
+        };
+        let (title, duration) = match &entry.track {
+            QueuedTrack::Resolved(track) => (track.title.as_str(), track.duration_text()),
+            QueuedTrack::Resolving(_, label) => (label.as_str(), "resolving".to_string()),
+        };
+        let requester = match entry.request {
+            Some(request) => format!(
+                " - {} {}",
+                display_name(&ctx.cache, guild_id, request.user_id),
+                age(request.requested_at)
+            ),
+            None => String::new(),
+        };
+        lines.push(format!(
+            "{}. \"{}\" [{}]{} - in {}{}",
+            number,
+            truncate(title, MAX_TITLE_LENGTH),
+            duration,
+            requester,
+            format_duration(eta),
+            if eta_known { "" } else { "+" }
+        ));
+
+        match &entry.track {
+            QueuedTrack::Resolved(track) if track.duration.is_some() && !track.is_live => {
+                eta += track.duration.unwrap_or_default()
+            }
+            _ => eta_known = false,
+        }
     }
-    for title in queue.iter().take(cmp::min(5, queue.len())) {
-        output.push_str(title);
-        output.push_str("\n\n");
+
+    let pages = cmp::max(1, lines.len().div_ceil(QUEUE_PAGE_SIZE));
+    let page = cmp::min(page, pages);
+    let mut footer = format!(
+        "\nPage {}/{} | {} queued, {} from the autoplaylist | Total {}{} | Loop: {}",
+        page,
+        pages,
+        regular_index,
+        autoplaylist_index,
+        format_duration(eta),
+        if eta_known { "" } else { "+" },
+        loop_mode.name()
+    );
+    if fair {
+        footer.push_str(" | Fair queue");
+    }
+    if pages > 1 {
+        footer.push_str("\nUse queue <page> to see more");
     }
-    output.push_str("```");
-    let _ = msg.channel_id.say(&ctx.http, output);
+
+    let mut output = header;
+    if lines.is_empty() {
+        output.push_str("The queue is empty\n");
+    }
+    // Entries are cut short rather than splitting the reply, so it always fits one message.
+    let limit = MAX_MESSAGE_LENGTH - "``````".len() - footer.len();
+    for line in lines
+        .iter()
+        .skip((page - 1) * QUEUE_PAGE_SIZE)
+        .take(QUEUE_PAGE_SIZE)
+    {
+        if output.len() + line.len() + 1 > limit {
+            break;
+        }
+        output.push_str(line);
+        output.push('\n');
+    }
+    output.push_str(&footer);
+    let _ = msg.channel_id.say(&ctx.http, format!("```{}```", output));
 
     Ok(())
 }
 
+/// Shortens `text` to at most `max` characters, marking the cut with `...`.
+fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        text.to_string()
+    } else {
+        let mut truncated: String = text.chars().take(max - 3).collect();
+        truncated.push_str("...");
+        truncated
+    }
+}
+
+/// Skips right away for the track's requester and DJs. Everyone else listening votes, and the
+/// track is skipped once `skip_vote_percent` of the listeners voted for it.
 #[command]
+#[only_in(guilds)]
 pub fn skip(ctx: &mut Context, msg: &Message) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+
+    let (voice_manager, percent) = {
+        let share_map = ctx.data.read();
+        let config_loader = share_map
+            .get::<ConfigLoader>()
+            .expect("Expected ConfigLoader in ShareMap.");
+        let percent = CONF_SKIP_VOTE_PERCENT.get_guild_value(&mut config_loader.lock(), guild_id);
+        (
+            share_map
+                .get::<VoiceManager>()
+                .expect("Expected VoiceManager in ShareMap.")
+                .clone(),
+            cmp::min(percent, 100),
+        )
+    };
+
+    let (client_voice_manager, player) = {
+        let voice_manager_locked = voice_manager.lock();
+        (
+            voice_manager_locked.voice_manager.clone(),
+            voice_manager_locked.players.existing(guild_id),
+        )
+    };
+    let listening = match client_voice_manager
+        .lock()
+        .get(guild_id)
+        .and_then(|handler| handler.channel_id)
+    {
+        Some(channel_id) => listeners(ctx, guild_id, channel_id),
+        None => Vec::new(),
+    };
+    let dj = permissions::role(ctx, msg, guild_id) >= Role::Dj;
+
+    let author_id = msg.author.id;
+    let vote = player.as_ref().and_then(|player| {
+        player
+            .query(move |property| {
+                let now_playing = property.current_audio.as_mut()?;
+                let title = now_playing.track.describe();
+                let requested = now_playing
+                    .request
+                    .is_some_and(|request| request.user_id == author_id);
+                Some(if requested || dj || percent == 0 {
+                    (format!("Skipped {}", title), true)
+                } else if !listening.contains(&author_id) {
+                    (
+                        "Join the voice channel to vote for skipping".to_string(),
+                        false,
+                    )
+                } else {
+                    now_playing.skip_votes.insert(author_id);
+                    // Votes of listeners who left since don't count.
+                    let votes = now_playing
+                        .skip_votes
+                        .iter()
+                        .filter(|user_id| listening.contains(user_id))
+                        .count() as u64;
+                    let needed = cmp::max(1, (listening.len() as u64 * percent).div_ceil(100));
+                    if votes >= needed {
+                        (format!("Vote passed, skipped {}", title), true)
+                    } else {
+                        (
+                            format!("Voted to skip {} ({}/{})", title, votes, needed),
+                            false,
+                        )
+                    }
+                })
+            })
+            .flatten()
+    });
+    let output = match (vote, player) {
+        (Some((output, true)), Some(player)) => {
+            player.skip();
+            output
+        }
+        (Some((output, _)), _) => output,
+        (None, _) => "Nothing is playing".to_string(),
+    };
+    let _ = msg.channel_id.say(&ctx.http, format!("```{}```", output));
+
+    Ok(())
+}
+
+#[command("loop")]
+pub fn loop_mode(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+    let loop_mode = if args.is_empty() {
+        None
+    } else {
+        match args
+            .single::<String>()
+            .ok()
+            .and_then(|name| LoopMode::parse(&name))
+        {
+            Some(loop_mode) => Some(loop_mode),
+            None => {
+                msg.channel_id
+                    .say(&ctx.http, "```Usage: loop [off|track|queue]```")?;
+                return Ok(());
+            }
+        }
+    };
+    if loop_mode.is_some() && !require_dj(ctx, msg, guild_id, "change the loop mode")? {
+        return Ok(());
+    }
+
     let voice_manager = {
-        let mut share_map = ctx.data.write();
+        let share_map = ctx.data.read();
         share_map
-            .get_mut::<VoiceManager>()
+            .get::<VoiceManager>()
             .expect("Expected VoiceManager in ShareMap.")
             .clone()
     };
 
-    let config_loader = {
-        let mut share_map = ctx.data.write();
-        share_map
-            .get_mut::<ConfigLoader>()
+    // Only changing the loop mode needs a player, there is nothing to show without one.
+    let players = voice_manager.lock().players.clone();
+    let player = match loop_mode {
+        Some(_) => Some(players.get(guild_id)),
+        None => players.existing(guild_id),
+    };
+    let output = match player {
+        Some(player) => player
+            .query(move |property| match loop_mode {
+                Some(loop_mode) => {
+                    property.loop_mode = loop_mode;
+                    format!("Loop set to {}", loop_mode.name())
+                }
+                None => format!("Loop is {}", property.loop_mode.name()),
+            })
+            .unwrap_or_default(),
+        None => format!("Loop is {}", LoopMode::Off.name()),
+    };
+    let _ = msg.channel_id.say(&ctx.http, format!("```{}```", output));
+
+    Ok(())
+}
+
+#[command]
+pub fn remove(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+    let index = match queue_position(&mut args) {
+        Some(index) => index,
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "```Usage: remove <position>```")?;
+            return Ok(());
+        }
+    };
+
+    let dj = permissions::role(ctx, msg, guild_id) >= Role::Dj;
+    let author_id = msg.author.id;
+    let removed = query_playlist(ctx, guild_id, move |playlist| {
+        playlist.remove_if(index, |request| {
+            dj || request.is_some_and(|request| request.user_id == author_id)
+        })
+    });
+    let output = match removed.flatten() {
+        Some(Ok(track)) => format!("Removed {}", track.describe()),
+        Some(Err(track)) => format!(
+            "{} was requested by someone else, only DJs can remove it",
+            track.describe()
+        ),
+        None => format!("There is no entry {} in the queue", index + 1),
+    };
+    let _ = msg.channel_id.say(&ctx.http, format!("```{}```", output));
+
+    Ok(())
+}
+
+#[command("move")]
+#[checks(DJ)]
+pub fn move_entry(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+    let (from, to) = match (queue_position(&mut args), queue_position(&mut args)) {
+        (Some(from), Some(to)) => (from, to),
+        _ => {
+            msg.channel_id
+                .say(&ctx.http, "```Usage: move <from> <to>```")?;
+            return Ok(());
+        }
+    };
+
+    let output = query_playlist(ctx, guild_id, move |playlist| {
+        if playlist.is_fair() {
+            return "The fair queue decides the order, turn it off to move entries".to_string();
+        }
+        match playlist.move_entry(from, to) {
+            Some(track) => format!("Moved {} to position {}", track.describe(), to + 1),
+            None => "Both positions must be in the queue".to_string(),
+        }
+    })
+    .unwrap_or_else(|| "Both positions must be in the queue".to_string());
+    let _ = msg.channel_id.say(&ctx.http, format!("```{}```", output));
+
+    Ok(())
+}
+
+#[command]
+#[checks(DJ)]
+pub fn clear(ctx: &mut Context, msg: &Message) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+
+    let count = query_playlist(ctx, guild_id, |playlist| playlist.clear()).unwrap_or(0);
+    let _ = msg.channel_id.say(
+        &ctx.http,
+        format!("```Removed {} entries from the queue```", count),
+    );
+
+    Ok(())
+}
+
+#[command]
+#[checks(DJ)]
+pub fn shuffle(ctx: &mut Context, msg: &Message) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+
+    query_playlist(ctx, guild_id, |playlist| playlist.shuffle());
+    let _ = msg.channel_id.say(&ctx.http, "```Shuffled the queue```");
+
+    Ok(())
+}
+
+#[command]
+#[checks(DJ)]
+pub fn skipto(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+    let index = match queue_position(&mut args) {
+        Some(index) => index,
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "```Usage: skipto <position>```")?;
+            return Ok(());
+        }
+    };
+
+    match query_playlist(ctx, guild_id, move |playlist| playlist.skip_to(index)).flatten() {
+        Some(skipped) => {
+            if let Some(player) = guild_player(ctx, guild_id) {
+                player.skip();
+            }
+            let _ = msg.channel_id.say(
+                &ctx.http,
+                format!("```Skipped the current track and {} entries```", skipped),
+            );
+        }
+        None => {
+            let _ = msg.channel_id.say(
+                &ctx.http,
+                format!("```There is no entry {} in the queue```", index + 1),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a 1-based queue position as shown by `queue` and returns it 0-based.
+fn queue_position(args: &mut Args) -> Option<usize> {
+    match args.single::<usize>() {
+        Ok(position) if position > 0 => Some(position - 1),
+        _ => None,
+    }
+}
+
+/// The guild's player, if the guild has one.
+fn guild_player(ctx: &Context, guild_id: GuildId) -> Option<Player> {
+    let share_map = ctx.data.read();
+    let voice_manager = share_map
+        .get::<VoiceManager>()
+        .expect("Expected VoiceManager in ShareMap.")
+        .lock();
+    voice_manager.players.existing(guild_id)
+}
+
+/// Runs `query` on the guild's playlist on its player thread, if the guild has a player.
+/// Positions in the playlist depend on whether the fair queue is on, so the setting is applied
+/// first.
+fn query_playlist<R, Q>(ctx: &Context, guild_id: GuildId, query: Q) -> Option<R>
+where
+    R: Send + 'static,
+    Q: FnOnce(&mut playlist::Playlist) -> R + Send + 'static,
+{
+    let fair = {
+        let share_map = ctx.data.read();
+        let mut config_loader = share_map
+            .get::<ConfigLoader>()
             .expect("Expected ConfigLoader in ShareMap.")
+            .lock();
+        CONF_FAIR_QUEUE.get_guild_value(&mut config_loader, guild_id)
+    };
+    guild_player(ctx, guild_id)?.query(move |property| {
+        property.playlist.set_fair(fair);
+        query(&mut property.playlist)
+    })
+}
+
+/// Replies that the DJ role is needed unless the author has it. For commands that only need
+/// the role for some of their uses, the others are covered by the `DJ` check.
+fn require_dj(
+    ctx: &Context,
+    msg: &Message,
+    guild_id: GuildId,
+    action: &str,
+) -> Result<bool, SerenityError> {
+    if permissions::role(ctx, msg, guild_id) >= Role::Dj {
+        return Ok(true);
+    }
+    msg.channel_id.say(
+        &ctx.http,
+        format!("```You need the DJ role to {}```", action),
+    )?;
+    Ok(false)
+}
+
+#[command]
+pub fn volume(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let volume = if args.is_empty() {
+        None
+    } else {
+        match args.single::<u64>() {
+            Ok(volume) if volume <= MAX_VOLUME => Some(volume),
+            _ => {
+                msg.channel_id.say(
+                    &ctx.http,
+                    format!("```Volume must be between 0 and {}```", MAX_VOLUME),
+                )?;
+                return Ok(());
+            }
+        }
+    };
+
+    let voice_manager = {
+        let share_map = ctx.data.read();
+        share_map
+            .get::<VoiceManager>()
+            .expect("Expected VoiceManager in ShareMap.")
             .clone()
     };
 
-    let guild_id = msg.guild_id.unwrap();
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+    if volume.is_some() && !require_dj(ctx, msg, guild_id, "change the volume")? {
+        return Ok(());
+    }
+
+    // Only changing the volume needs a player, without one it is the guild's default.
+    let players = voice_manager.lock().players.clone();
+    let player = match volume {
+        Some(_) => Some(players.get(guild_id)),
+        None => players.existing(guild_id),
+    };
+    let output = match player {
+        Some(player) => player
+            .query(move |property| match volume {
+                Some(volume) => {
+                    property.volume = volume;
+                    if let Some(now_playing) = &property.current_audio {
+                        now_playing.audio.lock().volume(volume as f32 / 100.0);
+                    }
+                    format!("```Volume set to {}%```", volume)
+                }
+                None => format!("```Volume is {}%```", property.volume),
+            })
+            .unwrap_or_default(),
+        None => {
+            let share_map = ctx.data.read();
+            let mut config_loader = share_map
+                .get::<ConfigLoader>()
+                .expect("Expected ConfigLoader in ShareMap.")
+                .lock();
+            format!(
+                "```Volume is {}%```",
+                CONF_DEFAULT_VOLUME.get_guild_value(&mut config_loader, guild_id)
+            )
+        }
+    };
+    let _ = msg.channel_id.say(&ctx.http, output);
+
+    Ok(())
+}
 
-    let voice_manager_locked = voice_manager.lock();
-    let mut manager = voice_manager_locked.voice_manager.lock();
-    let mut voice_manager_properties_locked = voice_manager_locked.properties.lock();
-    let property = voice_manager_properties_locked
-        .entry(guild_id)
-        .or_insert_with(|| VoiceManagerProperties::new(&mut config_loader.lock()));
+#[command]
+#[checks(DJ)]
+pub fn pause(ctx: &mut Context, msg: &Message) -> CommandResult {
+    set_paused(ctx, msg, true)
+}
+
+#[command]
+#[checks(DJ)]
+pub fn resume(ctx: &mut Context, msg: &Message) -> CommandResult {
+    set_paused(ctx, msg, false)
+}
+
+fn set_paused(ctx: &mut Context, msg: &Message, paused: bool) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+
+    let output = match guild_player(ctx, guild_id).and_then(|player| player.pause(paused)) {
+        Some((track, true)) if paused => format!("Paused {}", track.describe()),
+        Some((track, true)) => format!("Resumed {}", track.describe()),
+        Some((_, false)) if paused => "Already paused".to_string(),
+        Some((_, false)) => "Already playing".to_string(),
+        None => "Nothing is playing".to_string(),
+    };
+    let _ = msg.channel_id.say(&ctx.http, format!("```{}```", output));
+
+    Ok(())
+}
 
-    property.current_audio = None;
+#[command]
+#[aliases(nowplaying)]
+pub fn np(ctx: &mut Context, msg: &Message) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
 
-    if let Some(handler) = manager.get_mut(guild_id) {
-        handler.stop();
+    let now_playing = guild_player(ctx, guild_id).and_then(|player| {
+        player
+            .query(|property| {
+                property.current_audio.as_ref().map(|now_playing| {
+                    (
+                        now_playing.track.clone(),
+                        now_playing.request,
+                        now_playing.elapsed(),
+                        now_playing.audio.lock().playing,
+                        property.loop_mode,
+                    )
+                })
+            })
+            .flatten()
+    });
+    let output = match now_playing {
+        Some((track, request, elapsed, playing, loop_mode)) => {
+            let progress = match track.duration {
+                Some(duration) if !track.is_live => format!(
+                    "{} {} / {}",
+                    progress_bar(elapsed, duration),
+                    format_duration(elapsed),
+                    format_duration(duration)
+                ),
+                _ => format!("{} / {}", format_duration(elapsed), track.duration_text()),
+            };
+            let state = if playing { "Playing" } else { "Paused" };
+            let requester = match request {
+                Some(request) => {
+                    let mut requester = format!(
+                        "{} {}",
+                        display_name(&ctx.cache, guild_id, request.user_id),
+                        age(request.requested_at)
+                    );
+                    if let Some(message_id) = request.message_id {
+                        requester.push_str(&format!(
+                            "\nhttps://discord.com/channels/{}/{}/{}",
+                            guild_id, request.channel_id, message_id
+                        ));
+                    }
+                    requester
+                }
+                None => "autoplaylist".to_string(),
+            };
+            format!(
+                "{} {}\n{}\nLoop: {}\n{}\nRequested by {}",
+                state,
+                track.describe(),
+                progress,
+                loop_mode.name(),
+                track.webpage_url,
+                requester
+            )
+        }
+        None => "Nothing is playing".to_string(),
     };
+    let _ = msg.channel_id.say(&ctx.http, format!("```{}```", output));
 
     Ok(())
 }
 
-type MusicResult = (LockedAudio, String, Option<ChannelId>);
+#[command]
+#[checks(DJ)]
+pub fn seek(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let position = match args
+        .single::<String>()
+        .ok()
+        .and_then(|text| parse_timestamp(&text))
+    {
+        Some(position) => position,
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "```Usage: seek <[h:]m:ss or seconds>```")?;
+            return Ok(());
+        }
+    };
+
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+
+    let (player, client_voice_manager, youtube_dl) = {
+        let share_map = ctx.data.read();
+        let voice_manager = share_map
+            .get::<VoiceManager>()
+            .expect("Expected VoiceManager in ShareMap.")
+            .lock();
+        (
+            voice_manager.players.existing(guild_id),
+            voice_manager.voice_manager.clone(),
+            voice_manager.youtube_dl.clone(),
+        )
+    };
+    let current = player.and_then(|player| {
+        player
+            .query(|property| {
+                property
+                    .current_audio
+                    .as_ref()
+                    .map(|now_playing| now_playing.track.clone())
+            })
+            .flatten()
+            .map(|track| (player, track))
+    });
 
-fn play_music(
-    handler: &mut Handler,
-    playlist: &mut playlist::Playlist,
-) -> Result<MusicResult, (String, Option<ChannelId>)> {
-    handler.deafen(true);
-    if let Some((title, url, channel_id)) = playlist.poll() {
-        match voice::ytdl(&url) {
-            Ok(source) => {
-                println!("{}: Start playing {} {}", handler.guild_id, title, url);
-                let audio = handler.play_only(source);
-                return Ok((audio, title, channel_id));
+    let output = match current {
+        Some((_, track)) if track.is_live => "Can't seek in a live stream".to_string(),
+        Some((_, track)) if track.duration.is_some_and(|duration| position >= duration) => {
+            format!("The track is only {} long", track.duration_text())
+        }
+        // Starting the stream takes a while, so it happens before the player is asked to switch.
+        Some((player, track)) => {
+            match youtube_dl.stream(&track.webpage_url, position, Duration::default()) {
+                Ok(source) => {
+                    let events = player.sender();
+                    player
+                        .query(move |property| {
+                            let volume = property.volume;
+                            let now_playing = match property.current_audio.as_mut() {
+                                Some(now_playing)
+                                    if now_playing.track.webpage_url == track.webpage_url =>
+                                {
+                                    now_playing
+                                }
+                                _ => return format!("{} isn't playing anymore", track.describe()),
+                            };
+                            let mut manager = client_voice_manager.lock();
+                            let handler = match manager.get_mut(guild_id) {
+                                Some(handler) => handler,
+                                None => return "Nothing is playing".to_string(),
+                            };
+                            let paused = !now_playing.audio.lock().playing;
+                            let (source, stream) =
+                                NotifyingSource::wrap(source, events, Duration::default());
+                            let audio = handler.play_only(source);
+                            {
+                                let mut audio = audio.lock();
+                                audio.volume(volume as f32 / 100.0);
+                                if paused {
+                                    audio.pause();
+                                }
+                            }
+                            // The replaced stream reports its end, but the track goes on.
+                            now_playing.audio = audio;
+                            now_playing.offset = position;
+                            now_playing.stream = stream;
+                            format!("Jumped to {}", format_duration(position))
+                        })
+                        .unwrap_or_default()
+                }
+                Err(err) => format!("Couldn't seek: {}", err),
             }
-            Err(_) => return Err((format!("Couldn't play {}", title), channel_id)),
         }
+        None => "Nothing is playing".to_string(),
+    };
+    let _ = msg.channel_id.say(&ctx.http, format!("```{}```", output));
+
+    Ok(())
+}
+
+/// Text progress bar like `[=======>------------]`.
+fn progress_bar(elapsed: Duration, total: Duration) -> String {
+    const WIDTH: usize = 20;
+    let filled = if total > Duration::default() {
+        cmp::min(
+            WIDTH,
+            (elapsed.as_secs_f64() / total.as_secs_f64() * WIDTH as f64) as usize,
+        )
+    } else {
+        0
+    };
+    format!("[{}>{}]", "=".repeat(filled), "-".repeat(WIDTH - filled))
+}
+
+/// The user's nickname in the guild, falling back to the username.
+fn display_name(cache: &CacheRwLock, guild_id: GuildId, user_id: UserId) -> String {
+    if let Some(guild) = guild_id.to_guild_cached(cache) {
+        if let Some(member) = guild.read().members.get(&user_id) {
+            return member.display_name().to_string();
+        }
+    }
+    match user_id.to_user_cached(cache) {
+        Some(user) => user.read().name.clone(),
+        None => user_id.to_string(),
+    }
+}
+
+/// How long ago `time` was, in its largest unit, e.g. `5m ago`.
+fn age(time: SystemTime) -> String {
+    let secs = time.elapsed().unwrap_or_default().as_secs();
+    match secs {
+        0..=59 => format!("{}s ago", secs),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86_399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86_400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_bar_fills_with_the_elapsed_share() {
+        let bar = |elapsed: u64, total: u64| {
+            progress_bar(Duration::from_secs(elapsed), Duration::from_secs(total))
+        };
+        assert_eq!(bar(0, 100), "[>--------------------]");
+        assert_eq!(bar(50, 100), "[==========>----------]");
+        assert_eq!(bar(100, 100), "[====================>]");
+        // Elapsed time can run past a duration the extractor got slightly wrong.
+        assert_eq!(bar(150, 100), "[====================>]");
+        assert_eq!(bar(10, 0), "[>--------------------]");
     }
-    Err(("".to_string(), None))
 }
@@ -1,22 +1,465 @@
+use crate::music::resolver::TrackResolver;
+use crate::music::track::TrackInfo;
+use serde_json::Value;
+use serenity::prelude::RwLock;
+use serenity::voice;
+use serenity::voice::AudioSource;
+use std::cmp;
+use std::fmt;
+use std::io;
 use std::io::Read;
-use std::process::{Command, Stdio};
-
-pub fn get_title(uri: &str) -> Result<String, ()> {
-    let ytdl_args = ["-e", "--no-playlist", uri];
-
-    let youtube_dl = Command::new("youtube-dl")
-        .args(&ytdl_args)
-        .stdin(Stdio::null())
-        .stderr(Stdio::null())
-        .stdout(Stdio::piped())
-        .spawn()
-        .unwrap();
-
-    let output = &mut String::new();
-    let _ = youtube_dl.stdout.ok_or(())?.read_to_string(output);
-    if !output.is_empty() {
-        Ok(output.trim().to_string())
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub(crate) enum YtdlError {
+    BinaryMissing(String),
+    Spawn(io::Error),
+    Timeout(Duration),
+    Failed(Option<i32>, String),
+    UnsupportedUrl,
+    GeoBlocked,
+    PrivateVideo,
+    InvalidOutput,
+}
+
+impl YtdlError {
+    /// Turns a failed extractor run into the most specific error its stderr allows.
+    fn from_failure(status: ExitStatus, stderr: &str) -> Self {
+        let lowercase = stderr.to_lowercase();
+        if lowercase.contains("unsupported url") {
+            YtdlError::UnsupportedUrl
+        } else if lowercase.contains("in your country") || lowercase.contains("geo restrict") {
+            YtdlError::GeoBlocked
+        } else if lowercase.contains("private video") || lowercase.contains("video is private") {
+            YtdlError::PrivateVideo
+        } else {
+            let message = stderr
+                .lines()
+                .rev()
+                .find(|line| !line.trim().is_empty())
+                .unwrap_or_default()
+                .trim();
+            YtdlError::Failed(status.code(), message.to_string())
+        }
+    }
+
+    /// Whether the error is down to the URL itself, so looking it up again won't help. Other
+    /// errors, e.g. a missing binary, a timeout or a failure the extractor doesn't explain, may
+    /// be a network problem affecting every lookup alike.
+    pub(crate) fn is_url_specific(&self) -> bool {
+        matches!(
+            self,
+            YtdlError::UnsupportedUrl | YtdlError::GeoBlocked | YtdlError::PrivateVideo
+        )
+    }
+}
+
+impl fmt::Display for YtdlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            YtdlError::BinaryMissing(executable) => write!(f, "{} is not installed", executable),
+            YtdlError::Spawn(err) => write!(f, "couldn't start the extractor ({})", err),
+            YtdlError::Timeout(timeout) => {
+                write!(f, "lookup timed out after {}s", timeout.as_secs())
+            }
+            YtdlError::Failed(Some(code), message) if !message.is_empty() => {
+                write!(f, "extractor exited with {} ({})", code, message)
+            }
+            YtdlError::Failed(Some(code), _) => write!(f, "extractor exited with {}", code),
+            YtdlError::Failed(None, _) => write!(f, "extractor was terminated"),
+            YtdlError::UnsupportedUrl => write!(f, "unsupported URL"),
+            YtdlError::GeoBlocked => write!(f, "not available in the bot's country"),
+            YtdlError::PrivateVideo => write!(f, "video is private"),
+            YtdlError::InvalidOutput => write!(f, "extractor returned no usable metadata"),
+        }
+    }
+}
+
+struct Settings {
+    executable: String,
+    args: Vec<String>,
+    timeout: Duration,
+    search_provider: String,
+}
+
+/// youtube-dl compatible extractor (youtube-dl, yt-dlp, ...) used for metadata and streaming.
+/// Shared by every guild, so the settings sit behind a lock to be swapped on a config reload.
+pub(crate) struct YoutubeDl {
+    settings: RwLock<Settings>,
+}
+
+impl YoutubeDl {
+    pub(crate) fn new(
+        executable: &str,
+        args: Vec<String>,
+        timeout: Duration,
+        search_provider: &str,
+    ) -> Self {
+        YoutubeDl {
+            settings: RwLock::new(Settings {
+                executable: executable.to_string(),
+                args,
+                timeout,
+                search_provider: search_provider.to_string(),
+            }),
+        }
+    }
+
+    /// Takes over the settings of `other`. Runs that already started keep their settings.
+    pub(crate) fn reconfigure(&self, other: YoutubeDl) {
+        *self.settings.write() = other.settings.into_inner();
+    }
+
+    fn timeout(&self) -> Duration {
+        self.settings.read().timeout
+    }
+
+    /// Pseudo URL the extractor resolves to the first `count` results for `query`, e.g.
+    /// `ytsearch5:never gonna give you up`.
+    pub(crate) fn search_url(&self, query: &str, count: usize) -> String {
+        format!(
+            "{}{}:{}",
+            self.settings.read().search_provider,
+            count,
+            query
+        )
+    }
+
+    /// Builds an extractor invocation: our defaults first so the configured arguments can
+    /// override them, then the URL.
+    fn command(&self, args: &[&str], uri: &str) -> Command {
+        let settings = self.settings.read();
+        let mut command = Command::new(&settings.executable);
+        command
+            .args(args)
+            .args(["--socket-timeout", &settings.timeout.as_secs().to_string()])
+            .args(&settings.args)
+            .arg(uri)
+            .stdin(Stdio::null())
+            .stderr(Stdio::piped())
+            .stdout(Stdio::piped());
+        command
+    }
+
+    fn spawn(&self, command: &mut Command) -> Result<Child, YtdlError> {
+        debug!("Running {:?}", command);
+        command.spawn().map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => {
+                YtdlError::BinaryMissing(self.settings.read().executable.clone())
+            }
+            _ => YtdlError::Spawn(err),
+        })
+    }
+
+    /// Runs the extractor and returns its trimmed stdout, killing it once `timeout` passes.
+    fn run(&self, args: &[&str], uri: &str, timeout: Duration) -> Result<String, YtdlError> {
+        let mut child = self.spawn(&mut self.command(args, uri))?;
+        let stdout = read_in_background(child.stdout.take());
+        let stderr = read_in_background(child.stderr.take());
+
+        // A timeout too long to count to never expires.
+        let deadline = Instant::now().checked_add(timeout);
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) if deadline.is_none_or(|deadline| Instant::now() < deadline) => {
+                    thread::sleep(Duration::from_millis(50))
+                }
+                Ok(None) => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(YtdlError::Timeout(timeout));
+                }
+                Err(err) => {
+                    let _ = child.kill();
+                    return Err(YtdlError::Spawn(err));
+                }
+            }
+        };
+
+        let stdout = stdout.join().unwrap_or_default();
+        if !status.success() {
+            let stderr = stderr.join().unwrap_or_default();
+            return Err(YtdlError::from_failure(status, &stderr));
+        }
+
+        let stdout = stdout.trim();
+        if stdout.is_empty() {
+            return Err(YtdlError::InvalidOutput);
+        }
+        Ok(stdout.to_string())
+    }
+
+    /// Streams `url` as 48kHz stereo PCM by piping the extractor into ffmpeg, starting `start`
+    /// into the track. The extractor output can't be seeked, so ffmpeg decodes and drops
+    /// everything before `start`. Up to `buffer` of audio is read ahead in the background, so a
+    /// stream started before it is played begins without a gap.
+    pub(crate) fn stream(
+        &self,
+        url: &str,
+        start: Duration,
+        buffer: Duration,
+    ) -> Result<Box<dyn AudioSource>, YtdlError> {
+        let mut extractor = self.spawn(
+            self.command(
+                &[
+                    "--no-playlist",
+                    "-f",
+                    "webm[abr>0]/bestaudio/best",
+                    "-R",
+                    "infinite",
+                ],
+                url,
+            )
+            .args(["-o", "-"])
+            .stderr(Stdio::null()),
+        )?;
+
+        let extractor_stdout = match extractor.stdout.take() {
+            Some(stdout) => stdout,
+            None => {
+                let _ = extractor.kill();
+                return Err(YtdlError::InvalidOutput);
+            }
+        };
+
+        let ffmpeg = Command::new("ffmpeg")
+            .args(["-ss", &format!("{:.3}", start.as_secs_f64()), "-i", "-"])
+            .args([
+                "-f",
+                "s16le",
+                "-ac",
+                "2",
+                "-ar",
+                "48000",
+                "-acodec",
+                "pcm_s16le",
+                "-",
+            ])
+            .stdin(extractor_stdout)
+            .stderr(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn();
+
+        match ffmpeg {
+            Ok(ffmpeg) => {
+                let processes = StreamProcesses { extractor, ffmpeg };
+                if buffer > Duration::default() {
+                    Ok(voice::pcm(true, ReadAhead::new(processes, buffer)))
+                } else {
+                    Ok(voice::pcm(true, processes))
+                }
+            }
+            Err(err) => {
+                let _ = extractor.kill();
+                let _ = extractor.wait();
+                Err(match err.kind() {
+                    io::ErrorKind::NotFound => YtdlError::BinaryMissing("ffmpeg".to_string()),
+                    _ => YtdlError::Spawn(err),
+                })
+            }
+        }
+    }
+}
+
+impl TrackResolver for YoutubeDl {
+    fn resolve(&self, url: &str) -> Result<TrackInfo, YtdlError> {
+        let json = self.run(&["--no-playlist", "--dump-json"], url, self.timeout())?;
+        let value = serde_json::from_str::<Value>(&json).map_err(|_| YtdlError::InvalidOutput)?;
+        TrackInfo::from_json(&value, url).ok_or(YtdlError::InvalidOutput)
+    }
+
+    fn search(&self, query: &str, count: usize) -> Result<Vec<TrackInfo>, YtdlError> {
+        let output = self.run(
+            &["--flat-playlist", "--dump-json"],
+            &self.search_url(query, count),
+            self.timeout(),
+        )?;
+        Ok(output
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+            .filter_map(|value| TrackInfo::from_json(&value, &entry_url(&value)))
+            .collect())
+    }
+
+    fn expand_playlist(
+        &self,
+        url: &str,
+        max_items: usize,
+    ) -> Result<(String, Vec<TrackInfo>), YtdlError> {
+        let json = self.run(
+            &[
+                "--yes-playlist",
+                "--flat-playlist",
+                "--dump-single-json",
+                "--playlist-end",
+                &max_items.to_string(),
+            ],
+            url,
+            self.timeout(),
+        )?;
+        let value = serde_json::from_str::<Value>(&json).map_err(|_| YtdlError::InvalidOutput)?;
+        let entries = match value["entries"].as_array() {
+            Some(entries) => entries
+                .iter()
+                .take(max_items)
+                .filter_map(|entry| TrackInfo::from_json(entry, &entry_url(entry)))
+                .collect(),
+            None => return Err(YtdlError::InvalidOutput),
+        };
+        let title = value["title"].as_str().unwrap_or(url).to_string();
+        Ok((title, entries))
+    }
+}
+
+/// Whether `url` points at a playlist rather than a single track. A video opened from a
+/// playlist or mix (`watch?v=...&list=...`) or a track opened from a set (`?in=.../sets/...`)
+/// is a single track, like youtube-dl's `--no-playlist` treats it.
+pub(crate) fn is_playlist_url(url: &str) -> bool {
+    let (path, query) = match url.find('?') {
+        Some(index) => url.split_at(index),
+        None => (url, ""),
+    };
+    (path.ends_with("/playlist") && query.contains("list=")) || path.contains("/sets/")
+}
+
+/// URL of a `--flat-playlist` entry. youtube-dl only reports the video id for YouTube entries.
+fn entry_url(entry: &Value) -> String {
+    let url = entry["url"].as_str().unwrap_or_default();
+    if !url.starts_with("http") && entry["ie_key"].as_str() == Some("Youtube") {
+        format!("https://www.youtube.com/watch?v={}", url)
     } else {
-        Err(())
+        url.to_string()
+    }
+}
+
+/// Drains a child pipe on its own thread so a chatty process can't fill the pipe and stall.
+fn read_in_background<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<String> {
+    thread::spawn(move || {
+        let mut output = String::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_string(&mut output);
+        }
+        output
+    })
+}
+
+/// Keeps both processes of a stream alive while it is played and reaps them afterwards.
+struct StreamProcesses {
+    extractor: Child,
+    ffmpeg: Child,
+}
+
+impl Read for StreamProcesses {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self.ffmpeg.stdout.as_mut() {
+            Some(stdout) => stdout.read(buffer),
+            None => Ok(0),
+        }
+    }
+}
+
+impl Drop for StreamProcesses {
+    fn drop(&mut self) {
+        for child in [&mut self.ffmpeg, &mut self.extractor] {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// 48kHz stereo 16-bit PCM, as streamed by ffmpeg.
+const PCM_BYTES_PER_SECOND: usize = 48_000 * 2 * 2;
+/// 20ms of audio, one frame of the voice connection.
+const CHUNK_SIZE: usize = PCM_BYTES_PER_SECOND / 50;
+/// Most audio kept ready by a `ReadAhead`, about 5MB.
+const MAX_READ_AHEAD: Duration = Duration::from_secs(30);
+
+/// Reads a stream on its own thread, keeping up to a fixed amount of it ready. Dropping it ends
+/// the thread, which drops the stream.
+struct ReadAhead {
+    chunks: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl ReadAhead {
+    fn new<R: Read + Send + 'static>(mut reader: R, buffer: Duration) -> Self {
+        let buffer = cmp::min(buffer, MAX_READ_AHEAD);
+        let capacity = (buffer.as_secs_f64() * PCM_BYTES_PER_SECOND as f64) as usize / CHUNK_SIZE;
+        let (sender, chunks) = mpsc::sync_channel(cmp::max(1, capacity));
+        thread::spawn(move || loop {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            match reader.read(&mut chunk) {
+                Ok(0) => return,
+                Ok(read) => {
+                    chunk.truncate(read);
+                    if sender.send(chunk).is_err() {
+                        return;
+                    }
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return,
+            }
+        });
+        ReadAhead {
+            chunks,
+            chunk: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+impl Read for ReadAhead {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.chunk.len() {
+            match self.chunks.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.position = 0;
+                }
+                // The stream ended.
+                Err(_) => return Ok(0),
+            }
+        }
+        let read = cmp::min(buffer.len(), self.chunk.len() - self.position);
+        buffer[..read].copy_from_slice(&self.chunk[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_playlists_are_expanded() {
+        assert!(is_playlist_url(
+            "https://www.youtube.com/playlist?list=PLx0sYbCqOb8TBPRdmBHs5Iftvv9TPboYG"
+        ));
+        assert!(is_playlist_url(
+            "https://music.youtube.com/playlist?list=OLAK5uy_k"
+        ));
+        assert!(is_playlist_url("https://soundcloud.com/artist/sets/album"));
+
+        assert!(!is_playlist_url(
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ"
+        ));
+        assert!(!is_playlist_url(
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ&list=RDdQw4w9WgXcQ&start_radio=1"
+        ));
+        assert!(!is_playlist_url(
+            "https://youtu.be/dQw4w9WgXcQ?list=PLx0sYbCqOb8TBPRdmBHs5Iftvv9TPboYG"
+        ));
+        assert!(!is_playlist_url("https://www.youtube.com/playlist"));
+        assert!(!is_playlist_url(
+            "https://soundcloud.com/artist/track?in=artist/sets/album"
+        ));
     }
 }
@@ -0,0 +1,153 @@
+use crate::configuration::ConfigLoader;
+use crate::configuration::{
+    IntegerOption, CONF_ADMIN_ROLE, CONF_BANNED_ROLE, CONF_DJ_ROLE, CONF_USER_ROLE,
+};
+use serenity::client::Context;
+use serenity::framework::standard::macros::check;
+use serenity::framework::standard::{Args, CheckResult, CommandOptions};
+use serenity::model::channel::Message;
+use serenity::model::guild::Guild;
+use serenity::model::id::{GuildId, RoleId};
+
+/// What a member may do with the bot, each role includes the ones before it.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// May not use any command, either because of the banned role or for lacking the user role.
+    Banned,
+    /// Plays music, votes to skip and manages their own queue entries.
+    User,
+    /// Controls playback for everyone and manages the whole queue.
+    Dj,
+    /// Changes the guild's configuration.
+    Admin,
+}
+
+impl Role {
+    fn name(self) -> &'static str {
+        match self {
+            Role::Banned => "banned",
+            Role::User => "user",
+            Role::Dj => "DJ",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+/// The role of the message's author in the guild. The guild's owner and administrators are
+/// always admins, everyone else gets the highest role the guild's configured roles grant them. A
+/// role that isn't configured is granted to nobody, except the user role which is granted to
+/// everyone.
+pub fn role(ctx: &Context, msg: &Message, guild_id: GuildId) -> Role {
+    let (admin_role, banned_role, dj_role, user_role) = {
+        let share_map = ctx.data.read();
+        let config_loader = share_map
+            .get::<ConfigLoader>()
+            .expect("Expected ConfigLoader in ShareMap.");
+        let mut config_loader = config_loader.lock();
+        let mut get = |option: IntegerOption| option.get_guild_value(&mut config_loader, guild_id);
+        (
+            get(CONF_ADMIN_ROLE),
+            get(CONF_BANNED_ROLE),
+            get(CONF_DJ_ROLE),
+            get(CONF_USER_ROLE),
+        )
+    };
+
+    let guild = match guild_id.to_guild_cached(&ctx.cache) {
+        Some(guild) => guild,
+        None => return Role::User,
+    };
+    let guild = guild.read();
+    let user_id = msg.author.id;
+    let roles = member_roles(&guild, msg);
+    let administrator = roles.iter().any(|role| {
+        guild
+            .roles
+            .get(role)
+            .is_some_and(|role| role.permissions.administrator())
+    });
+    if guild.owner_id == user_id
+        || administrator
+        || guild.member_permissions(user_id).administrator()
+    {
+        return Role::Admin;
+    }
+    let has_role = |role: u64| role != 0 && roles.contains(&RoleId(role));
+    if has_role(admin_role) {
+        Role::Admin
+    } else if has_role(banned_role) {
+        Role::Banned
+    } else if has_role(dj_role) {
+        Role::Dj
+    } else if user_role == 0 || has_role(user_role) {
+        Role::User
+    } else {
+        Role::Banned
+    }
+}
+
+/// The author's roles as sent along with the message, or from the cache if the message came
+/// without them. Large guilds don't have every member cached.
+fn member_roles(guild: &Guild, msg: &Message) -> Vec<RoleId> {
+    match &msg.member {
+        Some(member) => member.roles.clone(),
+        None => guild
+            .members
+            .get(&msg.author.id)
+            .map(|member| member.roles.clone())
+            .unwrap_or_default(),
+    }
+}
+
+/// Passes if the author has at least `required`. Commands outside of guilds always pass.
+fn require(ctx: &Context, msg: &Message, options: &CommandOptions, required: Role) -> CheckResult {
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return CheckResult::Success,
+    };
+    let role = role(ctx, msg, guild_id);
+    if role >= required {
+        CheckResult::Success
+    } else if role == Role::Banned {
+        CheckResult::new_user("You may not use music commands in this guild")
+    } else {
+        CheckResult::new_user(format!(
+            "You need the {} role to use {}",
+            required.name(),
+            options.names[0]
+        ))
+    }
+}
+
+#[check]
+#[name = "User"]
+pub fn user_check(
+    ctx: &mut Context,
+    msg: &Message,
+    _: &mut Args,
+    options: &CommandOptions,
+) -> CheckResult {
+    require(ctx, msg, options, Role::User)
+}
+
+#[check]
+#[name = "DJ"]
+pub fn dj_check(
+    ctx: &mut Context,
+    msg: &Message,
+    _: &mut Args,
+    options: &CommandOptions,
+) -> CheckResult {
+    require(ctx, msg, options, Role::Dj)
+}
+
+#[check]
+#[name = "Admin"]
+pub fn admin_check(
+    ctx: &mut Context,
+    msg: &Message,
+    _: &mut Args,
+    options: &CommandOptions,
+) -> CheckResult {
+    require(ctx, msg, options, Role::Admin)
+}
@@ -0,0 +1,396 @@
+use crate::configuration::{is_autoplaylist_name, parse_duration, ConfigLoader, MAX_STREAM_AHEAD};
+use crate::configuration::{BoolOption, DurationOption, IntegerOption, StringOption, OPTIONS};
+use crate::configuration::{
+    CONF_ADMIN_ROLE, CONF_ALONE_TIMEOUT, CONF_ANNOUNCE_CHANNEL, CONF_AUTOPLAYLIST_PATH,
+    CONF_BANNED_ROLE, CONF_CROSSFADE, CONF_DEFAULT_VOLUME, CONF_DJ_ROLE, CONF_FAIR_QUEUE,
+    CONF_IDLE_TIMEOUT, CONF_MAX_QUEUE_LENGTH, CONF_MAX_TRACK_DURATION, CONF_MAX_USER_QUEUE_LENGTH,
+    CONF_PAUSE_WHEN_ALONE, CONF_PREFIX, CONF_SKIP_VOTE_PERCENT, CONF_STAY_CONNECTED,
+    CONF_USER_ROLE,
+};
+use crate::logging;
+use crate::music::voice_client;
+use crate::permissions::ADMIN_CHECK;
+use serde_json::Value;
+use serenity::client::Context;
+use serenity::framework::standard::macros::command;
+use serenity::framework::standard::macros::group;
+use serenity::framework::standard::{Args, CommandResult};
+use serenity::framework::StandardFramework;
+use serenity::model::channel::Message;
+use serenity::model::guild::Guild;
+use serenity::model::id::{ChannelId, GuildId, RoleId};
+use serenity::prelude::{RwLock, ShareMap};
+use serenity::Client;
+use std::fs;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// A configuration option guild admins may override, and how its value is entered.
+enum Setting {
+    Text(StringOption<'static>),
+    Number(IntegerOption<'static>, u64),
+    Role(IntegerOption<'static>),
+    Channel(IntegerOption<'static>),
+    Toggle(BoolOption<'static>),
+    /// A duration with its maximum, if it has one.
+    Duration(DurationOption<'static>, Option<Duration>),
+}
+
+impl Setting {
+    fn key(&self) -> &'static str {
+        match self {
+            Setting::Text(option) => option.key(),
+            Setting::Number(option, _) | Setting::Role(option) | Setting::Channel(option) => {
+                option.key()
+            }
+            Setting::Toggle(option) => option.key(),
+            Setting::Duration(option, _) => option.key(),
+        }
+    }
+
+    /// Effective value for the guild, rendered for chat.
+    fn display(
+        &self,
+        ctx: &Context,
+        config_loader: &mut ConfigLoader,
+        guild_id: GuildId,
+    ) -> String {
+        match self {
+            Setting::Text(option) => option.get_guild_value(config_loader, guild_id),
+            Setting::Number(option, _) => {
+                option.get_guild_value(config_loader, guild_id).to_string()
+            }
+            Setting::Role(option) => match option.get_guild_value(config_loader, guild_id) {
+                0 => "none".to_string(),
+                id => RoleId(id)
+                    .to_role_cached(&ctx.cache)
+                    .map(|role| format!("@{}", role.name))
+                    .unwrap_or_else(|| id.to_string()),
+            },
+            Setting::Channel(option) => match option.get_guild_value(config_loader, guild_id) {
+                0 => "none".to_string(),
+                id => ChannelId(id)
+                    .name(&ctx.cache)
+                    .map(|name| format!("#{}", name))
+                    .unwrap_or_else(|| id.to_string()),
+            },
+            Setting::Toggle(option) => match option.get_guild_value(config_loader, guild_id) {
+                true => "on".to_string(),
+                false => "off".to_string(),
+            },
+            Setting::Duration(option, _) => {
+                format!(
+                    "{}s",
+                    option.get_guild_value(config_loader, guild_id).as_secs()
+                )
+            }
+        }
+    }
+
+    /// Parses user input into the value stored for this setting.
+    fn parse(&self, guild: &Guild, input: &str) -> Result<Value, String> {
+        match self {
+            Setting::Text(_) if input.is_empty() => Err("Value must not be empty".to_string()),
+            Setting::Text(option)
+                if option.key() == CONF_AUTOPLAYLIST_PATH.key() && !is_autoplaylist_name(input) =>
+            {
+                Err("Value must be the name of a file in the autoplaylist directory".to_string())
+            }
+            Setting::Text(_) => Ok(Value::from(input)),
+            Setting::Number(_, max) => match input.parse::<u64>() {
+                Ok(number) if number <= *max => Ok(Value::from(number)),
+                _ => Err(format!("Value must be a number between 0 and {}", max)),
+            },
+            Setting::Role(_) => {
+                let id = input.trim_start_matches("<@&").trim_end_matches('>');
+                id.parse::<u64>()
+                    .ok()
+                    .filter(|id| guild.roles.contains_key(&RoleId(*id)))
+                    .or_else(|| {
+                        guild
+                            .role_by_name(input.trim_start_matches('@'))
+                            .map(|role| role.id.0)
+                    })
+                    .map(Value::from)
+                    .ok_or_else(|| format!("{} is not a role", input))
+            }
+            Setting::Channel(_) => {
+                let id = input.trim_start_matches("<#").trim_end_matches('>');
+                id.parse::<u64>()
+                    .ok()
+                    .filter(|id| guild.channels.contains_key(&ChannelId(*id)))
+                    .or_else(|| {
+                        let name = input.trim_start_matches('#');
+                        guild
+                            .channels
+                            .values()
+                            .find(|channel| channel.read().name == name)
+                            .map(|channel| channel.read().id.0)
+                    })
+                    .map(Value::from)
+                    .ok_or_else(|| format!("{} is not a channel", input))
+            }
+            Setting::Toggle(_) => match input {
+                "on" | "true" | "yes" => Ok(Value::from(true)),
+                "off" | "false" | "no" => Ok(Value::from(false)),
+                _ => Err("Value must be on or off".to_string()),
+            },
+            Setting::Duration(_, max) => {
+                let value = input
+                    .parse::<u64>()
+                    .map_or_else(|_| Value::from(input), Value::from);
+                match (parse_duration(&value), max) {
+                    (Some(duration), Some(max)) if duration > *max => Err(format!(
+                        "Value must be a duration of at most {}s",
+                        max.as_secs()
+                    )),
+                    (Some(_), _) => Ok(value),
+                    (None, _) => Err("Value must be a duration such as 90s, 5m or 1h".to_string()),
+                }
+            }
+        }
+    }
+}
+
+const SETTINGS: [Setting; 18] = [
+    Setting::Text(CONF_PREFIX),
+    Setting::Text(CONF_AUTOPLAYLIST_PATH),
+    Setting::Number(CONF_DEFAULT_VOLUME, 200),
+    Setting::Role(CONF_ADMIN_ROLE),
+    Setting::Role(CONF_DJ_ROLE),
+    Setting::Role(CONF_USER_ROLE),
+    Setting::Role(CONF_BANNED_ROLE),
+    Setting::Channel(CONF_ANNOUNCE_CHANNEL),
+    Setting::Number(CONF_MAX_QUEUE_LENGTH, 10_000),
+    Setting::Number(CONF_SKIP_VOTE_PERCENT, 100),
+    Setting::Number(CONF_MAX_USER_QUEUE_LENGTH, 10_000),
+    Setting::Duration(CONF_MAX_TRACK_DURATION, None),
+    Setting::Toggle(CONF_FAIR_QUEUE),
+    Setting::Toggle(CONF_PAUSE_WHEN_ALONE),
+    Setting::Duration(CONF_ALONE_TIMEOUT, None),
+    Setting::Duration(CONF_IDLE_TIMEOUT, None),
+    Setting::Toggle(CONF_STAY_CONNECTED),
+    Setting::Duration(CONF_CROSSFADE, Some(MAX_STREAM_AHEAD)),
+];
+
+group!({
+    name: "settings",
+    options: {},
+    commands: [config, global_config, reload]
+});
+
+pub fn register_module(client: &mut Client, standard_framework: &mut StandardFramework) {
+    let data = client.data.clone();
+    let path = data
+        .read()
+        .get::<ConfigLoader>()
+        .expect("Expected ConfigLoader in ShareMap.")
+        .lock()
+        .path()
+        .to_string();
+
+    // Polls the modification time instead of relying on file system events, which works the
+    // same on every platform and with editors that replace the file on save.
+    thread::spawn(move || {
+        let modified = |path: &str| fs::metadata(path).and_then(|m| m.modified()).ok();
+        let mut last_modified: Option<SystemTime> = modified(&path);
+        loop {
+            thread::sleep(Duration::from_secs(2));
+            let current = modified(&path);
+            if current != last_modified {
+                last_modified = current;
+                match reload_config(&data) {
+                    Ok(_) => info!("Reloaded {}", path),
+                    Err(errors) => warn!("Not reloading {}: {}", path, errors.join(", ")),
+                }
+            }
+        }
+    });
+
+    standard_framework.group_add(&SETTINGS_GROUP);
+}
+
+/// Reads the configuration file again and applies it to the running modules. An invalid file
+/// is rejected as a whole and the current configuration stays in effect.
+pub fn reload_config(data: &Arc<RwLock<ShareMap>>) -> Result<(), Vec<String>> {
+    let config_loader = data
+        .read()
+        .get::<ConfigLoader>()
+        .expect("Expected ConfigLoader in ShareMap.")
+        .clone();
+    {
+        let mut config_loader = config_loader.lock();
+        config_loader.load()?;
+        logging::apply_config(&mut config_loader);
+    }
+    voice_client::reload_module(data);
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[checks(Admin)]
+pub fn config(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = match msg.guild(&ctx.cache) {
+        Some(guild) => guild,
+        None => return Ok(()),
+    };
+    let guild_id = guild.read().id;
+
+    let config_loader = {
+        let share_map = ctx.data.read();
+        share_map
+            .get::<ConfigLoader>()
+            .expect("Expected ConfigLoader in ShareMap.")
+            .clone()
+    };
+
+    let action = args
+        .single::<String>()
+        .unwrap_or_else(|_| "get".to_string());
+    let key = args.single::<String>().ok();
+    let setting = match &key {
+        Some(key) => match SETTINGS.iter().find(|setting| setting.key() == key) {
+            Some(setting) => Some(setting),
+            None => {
+                let keys: Vec<&str> = SETTINGS.iter().map(Setting::key).collect();
+                msg.channel_id.say(
+                    &ctx.http,
+                    format!("```Unknown key {}, available: {}```", key, keys.join(", ")),
+                )?;
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+
+    let mut config_loader = config_loader.lock();
+    let output = match (action.as_str(), setting) {
+        ("get", None) => SETTINGS
+            .iter()
+            .map(|setting| describe(ctx, &mut config_loader, guild_id, setting))
+            .collect::<Vec<String>>()
+            .join("\n"),
+        ("get", Some(setting)) => describe(ctx, &mut config_loader, guild_id, setting),
+        ("set", Some(setting)) => {
+            let input = args.rest().trim();
+            let value = setting.parse(&guild.read(), input);
+            match value {
+                Ok(value) => {
+                    match config_loader.set_guild_value(guild_id, setting.key(), Some(value)) {
+                        Ok(_) => describe(ctx, &mut config_loader, guild_id, setting),
+                        Err(err) => format!("Couldn't save the configuration: {}", err),
+                    }
+                }
+                Err(err) => err,
+            }
+        }
+        ("unset", Some(setting)) => {
+            match config_loader.set_guild_value(guild_id, setting.key(), None) {
+                Ok(_) => describe(ctx, &mut config_loader, guild_id, setting),
+                Err(err) => format!("Couldn't save the configuration: {}", err),
+            }
+        }
+        _ => "Usage: config get [key] | config set <key> <value> | config unset <key>".to_string(),
+    };
+    drop(config_loader);
+    voice_client::guild_config_changed(&ctx.data, guild_id);
+
+    let _ = msg.channel_id.say(&ctx.http, format!("```{}```", output));
+
+    Ok(())
+}
+
+fn describe(
+    ctx: &Context,
+    config_loader: &mut ConfigLoader,
+    guild_id: GuildId,
+    setting: &Setting,
+) -> String {
+    let source = match config_loader.get_guild_value(guild_id, setting.key()) {
+        Some(_) => "guild",
+        None => "global",
+    };
+    format!(
+        "{} = {} ({})",
+        setting.key(),
+        setting.display(ctx, config_loader, guild_id),
+        source
+    )
+}
+
+#[command("globalconfig")]
+#[owners_only]
+pub fn global_config(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let config_loader = {
+        let share_map = ctx.data.read();
+        share_map
+            .get::<ConfigLoader>()
+            .expect("Expected ConfigLoader in ShareMap.")
+            .clone()
+    };
+
+    let action = args
+        .single::<String>()
+        .unwrap_or_else(|_| "get".to_string());
+    let key = args.single::<String>().ok();
+    let option = match &key {
+        Some(key) => match OPTIONS.iter().find(|option| option.key() == key) {
+            Some(option) => Some(option),
+            None => {
+                msg.channel_id
+                    .say(&ctx.http, format!("```Unknown option {}```", key))?;
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+
+    let output = match (action.as_str(), option) {
+        ("get", None) => {
+            let mut config_loader = config_loader.lock();
+            OPTIONS
+                .iter()
+                .map(|option| format!("{} = {}", option.key(), option.display(&mut config_loader)))
+                .collect::<Vec<String>>()
+                .join("\n")
+        }
+        ("get", Some(option)) => format!(
+            "{} = {}",
+            option.key(),
+            option.display(&mut config_loader.lock())
+        ),
+        ("set", Some(option)) => {
+            let result = option.parse(args.rest().trim()).and_then(|value| {
+                config_loader.lock().set_value(option.key(), value)?;
+                reload_config(&ctx.data).map_err(|errors| errors.join(", "))
+            });
+            match result {
+                Ok(_) => format!(
+                    "{} = {}",
+                    option.key(),
+                    option.display(&mut config_loader.lock())
+                ),
+                Err(err) => err,
+            }
+        }
+        _ => "Usage: globalconfig get [option] | globalconfig set <option> <value>".to_string(),
+    };
+
+    let _ = msg.channel_id.say(&ctx.http, format!("```{}```", output));
+
+    Ok(())
+}
+
+#[command]
+#[owners_only]
+pub fn reload(ctx: &mut Context, msg: &Message) -> CommandResult {
+    let output = match reload_config(&ctx.data) {
+        Ok(_) => "Configuration reloaded".to_string(),
+        Err(errors) => format!("Configuration not reloaded:\n{}", errors.join("\n")),
+    };
+    let _ = msg.channel_id.say(&ctx.http, format!("```{}```", output));
+
+    Ok(())
+}